@@ -1,309 +1,5741 @@
 use ntex::web;
-use std::collections::{HashMap, HashSet};
-use std::sync:: Arc;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
 use serde::{Deserialize, Serialize};
-use std:: time::Instant;
+use std:: time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::str::FromStr;
+use std::net::IpAddr;
 use ntex::{fn_service, chain};
-use ntex::{channel::oneshot, rt};
+use ntex::{rt, time, util::Bytes};
 use ntex::service::{fn_factory_with_config, fn_shutdown, Service};
-use futures::channel::mpsc::{self, UnboundedSender};
-use futures::{SinkExt, StreamExt};
-use uuid;
-use tokio;
+use futures::channel::{mpsc::{self, UnboundedSender}, oneshot};
+use tokio::sync::mpsc::error::TrySendError;
+use futures::StreamExt;
+use tracing::{debug, error, info, info_span, instrument, warn, Instrument};
 
 
+/// Runtime settings, read once from the environment at startup.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Address the HTTP server listens on, as `host:port`.
+    bind: String,
+    /// How often each connection is pinged and checked for liveness.
+    heartbeat_interval: Duration,
+    /// How long a connection may go without a pong before it is closed.
+    client_timeout: Duration,
+    /// How long a player has to move before forfeiting the game.
+    turn_timeout: Duration,
+    /// How long a disconnected player's seat is held for them to resume.
+    reconnect_grace: Duration,
+    /// How long an open room waits for its second player before it is closed.
+    join_timeout: Duration,
+    /// How often abandoned and finished games are swept.
+    gc_interval: Duration,
+    /// How long a finished game is kept around for rematches and lookups.
+    finished_game_ttl: Duration,
+    /// Most games held at once; creating more is refused.
+    max_games: usize,
+    /// Moves per second each connection may send, also the burst size.
+    move_rate: u32,
+    /// SQLite database games are saved to; without one they live only in memory.
+    database: Option<String>,
+    /// How often games are written to the database.
+    persist_interval: Duration,
+    /// Origins allowed to open a websocket; empty allows any.
+    allowed_origins: Vec<String>,
+    /// Largest message accepted from a client, in bytes, whether it arrives
+    /// as a single frame or reassembled from fragments.
+    max_frame_size: usize,
+    /// Shared secret for the admin endpoints, sent as a bearer token;
+    /// without one they are disabled.
+    admin_token: Option<String>,
+    /// Commands the game server will queue before senders have to wait.
+    queue_capacity: usize,
+    /// How long a seated player may go without acting before being kicked.
+    idle_timeout: Duration,
+    /// File every accepted move is appended to as JSON lines; off when unset.
+    audit_log: Option<String>,
+    /// Most websocket connections open at once from a single IP address.
+    max_connections_per_ip: usize,
+    /// HTTP worker threads; ntex starts one per CPU when unset.
+    workers: Option<usize>,
+    /// PEM certificate chain and private key to serve HTTPS and `wss://`
+    /// with; plain HTTP when neither is set, and setting just one is an error.
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    /// Whether finished games between named players update their ratings.
+    /// Names are not authenticated, so anyone can play under someone
+    /// else's name and move their rating; only turn this on where that is
+    /// acceptable.
+    rated_games: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: "127.0.0.1:8080".into(),
+            heartbeat_interval: Duration::from_secs(5),
+            client_timeout: Duration::from_secs(30),
+            turn_timeout: Duration::from_secs(30),
+            reconnect_grace: Duration::from_secs(60),
+            join_timeout: Duration::from_secs(2 * 60),
+            gc_interval: Duration::from_secs(60),
+            finished_game_ttl: Duration::from_secs(10 * 60),
+            max_games: 1000,
+            move_rate: 5,
+            database: None,
+            persist_interval: Duration::from_secs(5),
+            allowed_origins: Vec::new(),
+            max_frame_size: 8 * 1024,
+            admin_token: None,
+            queue_capacity: 1024,
+            idle_timeout: Duration::from_secs(5 * 60),
+            audit_log: None,
+            max_connections_per_ip: 20,
+            workers: None,
+            tls_cert: None,
+            tls_key: None,
+            rated_games: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let default = Config::default();
+        // XO_BIND takes precedence over the separate address and port
+        let bind = std::env::var("XO_BIND").unwrap_or_else(|_| {
+            format!(
+                "{}:{}",
+                env_or("XO_BIND_ADDR", "127.0.0.1".to_string()),
+                env_or::<u16>("XO_PORT", 8080),
+            )
+        });
+        Config {
+            bind,
+            heartbeat_interval: Duration::from_secs(
+                env_or("XO_HEARTBEAT_INTERVAL_SECS", default.heartbeat_interval.as_secs())
+            ),
+            client_timeout: Duration::from_secs(
+                env_or("XO_CLIENT_TIMEOUT_SECS", default.client_timeout.as_secs())
+            ),
+            turn_timeout: Duration::from_secs(
+                env_or("XO_TURN_TIMEOUT_SECS", default.turn_timeout.as_secs())
+            ),
+            reconnect_grace: Duration::from_secs(
+                env_or("XO_RECONNECT_GRACE_SECS", default.reconnect_grace.as_secs())
+            ),
+            join_timeout: Duration::from_secs(
+                env_or("XO_JOIN_TIMEOUT_SECS", default.join_timeout.as_secs())
+            ),
+            gc_interval: Duration::from_secs(
+                env_or("XO_GC_INTERVAL_SECS", default.gc_interval.as_secs())
+            ),
+            finished_game_ttl: Duration::from_secs(
+                env_or("XO_FINISHED_GAME_TTL_SECS", default.finished_game_ttl.as_secs())
+            ),
+            max_games: env_or("XO_MAX_GAMES", default.max_games),
+            move_rate: env_or("XO_MOVE_RATE", default.move_rate),
+            database: std::env::var("XO_DATABASE").ok().filter(|path| !path.is_empty()),
+            persist_interval: Duration::from_secs(
+                env_or("XO_PERSIST_INTERVAL_SECS", default.persist_interval.as_secs())
+            ),
+            // Comma-separated, e.g. `https://xo.example.com,https://www.xo.example.com`
+            allowed_origins: std::env::var("XO_ALLOWED_ORIGINS")
+                .map(|origins| {
+                    origins.split(',')
+                        .map(|origin| origin.trim().to_string())
+                        .filter(|origin| !origin.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            max_frame_size: env_or("XO_MAX_FRAME_BYTES", default.max_frame_size),
+            admin_token: std::env::var("XO_ADMIN_TOKEN").ok().filter(|token| !token.is_empty()),
+            queue_capacity: env_or("XO_QUEUE_CAPACITY", default.queue_capacity).max(1),
+            idle_timeout: Duration::from_secs(
+                env_or("XO_IDLE_TIMEOUT_SECS", default.idle_timeout.as_secs())
+            ),
+            audit_log: std::env::var("XO_AUDIT_LOG").ok().filter(|path| !path.is_empty()),
+            max_connections_per_ip: env_or("XO_MAX_CONNECTIONS_PER_IP", default.max_connections_per_ip),
+            workers: std::env::var("XO_WORKERS").ok().and_then(|n| n.parse().ok()).filter(|n| *n > 0),
+            tls_cert: std::env::var("XO_TLS_CERT").ok().filter(|path| !path.is_empty()),
+            tls_key: std::env::var("XO_TLS_KEY").ok().filter(|path| !path.is_empty()),
+            rated_games: env_or("XO_RATED_GAMES", default.rated_games),
+        }
+    }
+}
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
 #[derive(Debug)]
 pub enum ClientMessage {
-    Id(String),
-    GameState(Room),
+    Id { id: String, token: String },
+    GameState(Arc<Room>),
     Error(String),
+    InvalidMove { code: ErrorCode, position: Vec<usize> },
+    /// A move out of turn, with whose turn it is so the client can resync.
+    NotYourTurn { current_player: Option<String>, x_turn: bool },
     YourTurn(bool),
+    /// Ask the connection to close itself for the given reason.
+    Close(CloseCause),
+    Chat { from: String, text: String, seq: u64, channel: ChatChannel },
+    DrawOffered { from: String },
+    MatchOver { winner: String, score: HashMap<String, u32> },
+    LobbyUpdate(Vec<GameSummary>),
+    /// A single move, for clients that track the board themselves.
+    MoveApplied { position: [usize; 2], symbol: char, x_turn: bool },
+    GameOver {
+        winner: Option<char>,
+        reason: GameOverReason,
+        line: Vec<[usize; 2]>,
+        duration_secs: Option<u64>,
+        think_times: HashMap<String, ThinkTime>,
+    },
+    /// Confirms to the mover that their move was applied.
+    MoveAck { position: [usize; 2] },
+    /// Someone took a seat in, or started watching, a game the recipient is in.
+    PlayerJoined { player_id: String },
+    /// Someone in a game the recipient is in lost their connection.
+    PlayerLeft { player_id: String },
+    /// A notice from the operators to everyone connected.
+    Announcement { text: String },
+    /// Tells a session what it became on joining a game.
+    Joined { game_id: String, role: Role, symbol: Option<char> },
+    /// Both players of a game that waited for them are ready, and play begins.
+    GameStarted { game_id: String },
+}
+
+/// Whether a game member plays or watches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Player,
+    Spectator,
 }
 
+/// Handle to the game server's mailbox. The mailbox is bounded, so a
+/// sender waits while it is full rather than letting it grow without limit.
+pub type ServerSender = tokio::sync::mpsc::Sender<ServerMessage>;
+
 #[derive(Debug)]
 pub enum ServerMessage {
     Connect {
-        id: String,
+        /// Resume token from an earlier connection, if the client has one.
+        token: Option<String>,
         sender: UnboundedSender<ClientMessage>,
+        /// Receives the player id this connection is bound to.
+        reply: oneshot::Sender<String>,
+        /// Whether the client asked for `MoveApplied` deltas after moves.
+        move_deltas: bool,
+        /// Address the connection came from, kept for moderation.
+        peer: Option<IpAddr>,
     },
     Disconnect(String),
+    /// Watch a public game over a connection that can only receive,
+    /// answering with the watcher's id, or `None` if there is no such game.
+    Watch {
+        game_id: String,
+        sender: UnboundedSender<ClientMessage>,
+        reply: oneshot::Sender<Option<String>>,
+    },
+    /// Give up a seat, or stop watching, while keeping the connection open.
+    LeaveGame {
+        player_id: String,
+        game_id: String,
+    },
+    ReleaseSession {
+        player_id: String,
+        generation: u64,
+    },
+    /// The features a session settled on in its `hello`.
+    SetFeatures {
+        player_id: String,
+        move_deltas: bool,
+    },
     JoinGame {
         player_id: String,
         game_id: String,
+        spectate: bool,
+        size: Option<usize>,
+        win_length: Option<usize>,
+        rounds: Option<usize>,
+        /// Rule set, used only if this join creates the game.
+        variant: Option<Variant>,
+        /// Used only if this join creates the game.
+        timeout_policy: Option<TimeoutPolicy>,
+        /// Whether both players must send `ready` before play starts;
+        /// used only if this join creates the game.
+        ready_check: Option<bool>,
+        name: Option<String>,
+        /// Join code, needed for private games.
+        code: Option<String>,
+    },
+    /// Start a new game against the server-controlled bot.
+    JoinBot {
+        player_id: String,
+        size: Option<usize>,
+        win_length: Option<usize>,
+        name: Option<String>,
+        timeout_policy: Option<TimeoutPolicy>,
+    },
+    /// Pair up with whoever is waiting for a game, or wait for the next player.
+    QuickMatch {
+        player_id: String,
+        /// Whose rating to match on; anonymous players count as new.
+        name: Option<String>,
     },
+    /// Pair off waiting players whose search windows have grown to meet.
+    Matchmake,
     MakeMove {
         player_id: String,
         game_id: String,
         position: Vec<usize>,
     },
+    Rematch {
+        player_id: String,
+        game_id: String,
+    },
+    Undo {
+        player_id: String,
+        game_id: String,
+    },
+    Chat {
+        player_id: String,
+        game_id: String,
+        text: String,
+    },
+    Resign {
+        player_id: String,
+        game_id: String,
+    },
+    /// A seated player is set to play, in games that wait for both.
+    Ready {
+        player_id: String,
+        game_id: String,
+    },
+    DrawOffer {
+        player_id: String,
+        game_id: String,
+    },
+    /// The opponent's answer to a pending draw offer.
+    DrawResponse {
+        player_id: String,
+        game_id: String,
+        accept: bool,
+    },
+    TurnTimeout {
+        game_id: String,
+        expected_turn: u64,
+    },
+    /// An open room's wait for a second player is up. `generation` tells
+    /// this wait apart from later ones, in the same room or a new one
+    /// reusing its id.
+    JoinTimeout {
+        game_id: String,
+        generation: u64,
+    },
+    GetGame {
+        game_id: String,
+        reply: oneshot::Sender<Option<Room>>,
+    },
+    GetMoves {
+        game_id: String,
+        reply: oneshot::Sender<Option<Vec<MoveRecord>>>,
+    },
+    GetPlayer {
+        name: String,
+        reply: oneshot::Sender<Option<PlayerRecord>>,
+    },
+    /// Play a game back to a session, one state per move.
+    Replay {
+        player_id: String,
+        game_id: String,
+        /// Pause between states; defaults to `DEFAULT_REPLAY_DELAY`.
+        delay_ms: Option<u64>,
+    },
+    Stats {
+        reply: oneshot::Sender<ServerStats>,
+    },
+    /// Answered with whether the server is taking new games.
+    Ping {
+        reply: oneshot::Sender<bool>,
+    },
+    SetDraining {
+        draining: bool,
+        reply: oneshot::Sender<()>,
+    },
+    /// Tell every connected session something, such as an upcoming restart.
+    Announce {
+        text: String,
+    },
+    Shutdown {
+        reply: oneshot::Sender<()>,
+    },
+    Gc,
+    ListGames {
+        /// Only games in this state; `Waiting` means rooms with a seat to take.
+        status: Option<GameStatus>,
+        sort: GameSort,
+        /// Games skipped, then at most `limit` returned, after sorting.
+        offset: usize,
+        limit: Option<usize>,
+        reply: oneshot::Sender<GamePage>,
+    },
+    /// Start or stop sending a session lobby updates.
+    SubscribeLobby {
+        player_id: String,
+        subscribe: bool,
+    },
+    /// Write every game to the database, if one is configured.
+    Persist,
+    CreateGame {
+        size: usize,
+        win_length: usize,
+        rounds: usize,
+        variant: Variant,
+        timeout_policy: TimeoutPolicy,
+        ready_check: bool,
+        /// Makes the game private to those who know the code.
+        code: Option<String>,
+        /// The new game id, or why none was created.
+        reply: oneshot::Sender<Result<String, String>>,
+    },
+    /// Remove a game and disconnect everyone in it, answering whether it existed.
+    ForceClose {
+        game_id: String,
+        reply: oneshot::Sender<bool>,
+    },
+    /// A game together with where each member is connected from.
+    AdminGame {
+        game_id: String,
+        reply: oneshot::Sender<Option<AdminGameView>>,
+    },
+}
+
+impl ServerMessage {
+    /// The variant's name, for logs that must not show the message itself.
+    fn kind(&self) -> &'static str {
+        match self {
+            ServerMessage::Connect { .. } => "Connect",
+            ServerMessage::Disconnect(_) => "Disconnect",
+            ServerMessage::Watch { .. } => "Watch",
+            ServerMessage::LeaveGame { .. } => "LeaveGame",
+            ServerMessage::ReleaseSession { .. } => "ReleaseSession",
+            ServerMessage::SetFeatures { .. } => "SetFeatures",
+            ServerMessage::JoinGame { .. } => "JoinGame",
+            ServerMessage::JoinBot { .. } => "JoinBot",
+            ServerMessage::QuickMatch { .. } => "QuickMatch",
+            ServerMessage::Matchmake => "Matchmake",
+            ServerMessage::MakeMove { .. } => "MakeMove",
+            ServerMessage::Rematch { .. } => "Rematch",
+            ServerMessage::Undo { .. } => "Undo",
+            ServerMessage::Chat { .. } => "Chat",
+            ServerMessage::Resign { .. } => "Resign",
+            ServerMessage::Ready { .. } => "Ready",
+            ServerMessage::DrawOffer { .. } => "DrawOffer",
+            ServerMessage::DrawResponse { .. } => "DrawResponse",
+            ServerMessage::TurnTimeout { .. } => "TurnTimeout",
+            ServerMessage::JoinTimeout { .. } => "JoinTimeout",
+            ServerMessage::GetGame { .. } => "GetGame",
+            ServerMessage::GetMoves { .. } => "GetMoves",
+            ServerMessage::GetPlayer { .. } => "GetPlayer",
+            ServerMessage::Replay { .. } => "Replay",
+            ServerMessage::Stats { .. } => "Stats",
+            ServerMessage::Ping { .. } => "Ping",
+            ServerMessage::SetDraining { .. } => "SetDraining",
+            ServerMessage::Announce { .. } => "Announce",
+            ServerMessage::Shutdown { .. } => "Shutdown",
+            ServerMessage::Gc => "Gc",
+            ServerMessage::ListGames { .. } => "ListGames",
+            ServerMessage::SubscribeLobby { .. } => "SubscribeLobby",
+            ServerMessage::Persist => "Persist",
+            ServerMessage::CreateGame { .. } => "CreateGame",
+            ServerMessage::ForceClose { .. } => "ForceClose",
+            ServerMessage::AdminGame { .. } => "AdminGame",
+        }
+    }
+
+    /// The game this message acts on, for messages scoped to a single game.
+    fn game_id(&self) -> Option<&str> {
+        match self {
+            ServerMessage::JoinGame { game_id, .. }
+            | ServerMessage::LeaveGame { game_id, .. }
+            | ServerMessage::MakeMove { game_id, .. }
+            | ServerMessage::Rematch { game_id, .. }
+            | ServerMessage::Undo { game_id, .. }
+            | ServerMessage::Chat { game_id, .. }
+            | ServerMessage::Resign { game_id, .. }
+            | ServerMessage::Ready { game_id, .. }
+            | ServerMessage::DrawOffer { game_id, .. }
+            | ServerMessage::DrawResponse { game_id, .. }
+            | ServerMessage::TurnTimeout { game_id, .. }
+            | ServerMessage::JoinTimeout { game_id, .. }
+            | ServerMessage::GetGame { game_id, .. }
+            | ServerMessage::GetMoves { game_id, .. }
+            | ServerMessage::Replay { game_id, .. }
+            | ServerMessage::Watch { game_id, .. } => Some(game_id),
+            _ => None,
+        }
+    }
+
+    /// The player taking a gameplay action, which resets their idle clock.
+    fn acting_player(&self) -> Option<&str> {
+        match self {
+            ServerMessage::JoinGame { player_id, .. }
+            | ServerMessage::JoinBot { player_id, .. }
+            | ServerMessage::QuickMatch { player_id, .. }
+            | ServerMessage::MakeMove { player_id, .. }
+            | ServerMessage::Rematch { player_id, .. }
+            | ServerMessage::Undo { player_id, .. }
+            | ServerMessage::Resign { player_id, .. }
+            | ServerMessage::Ready { player_id, .. }
+            | ServerMessage::DrawOffer { player_id, .. }
+            | ServerMessage::DrawResponse { player_id, .. } => Some(player_id),
+            _ => None,
+        }
+    }
+
+    /// The player a message comes from, for logs. Unlike `acting_player`
+    /// this includes chat, which does not count as activity.
+    fn sender(&self) -> Option<&str> {
+        match self {
+            ServerMessage::Chat { player_id, .. } => Some(player_id),
+            _ => self.acting_player(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct Room {
     game_id: String,
     players: HashSet<String>,
-    board: Vec<Vec<String>>,
+    symbols: HashMap<String, char>,
+    /// Display name of each seated player.
+    names: HashMap<String, String>,
+    /// Seated players who chose their own name, and so can be rated.
+    #[serde(skip)]
+    named: HashSet<String>,
+    #[serde(rename = "spectator_count", serialize_with = "serialize_len")]
+    spectators: HashSet<String>,
+    board: Vec<Vec<Cell>>,
     x_turn: bool,
+    /// The player who moved first this round, so the next round can hand
+    /// the first move to the other one.
+    started_by: Option<String>,
+    /// The player expected to move, filled in by `snapshot` for broadcasts.
+    current_player: Option<String>,
+    win_length: usize,
+    /// Games in the match; 1 for a single game.
+    rounds: usize,
+    variant: Variant,
+    timeout_policy: TimeoutPolicy,
+    /// Play waits, even with both seats filled, until both players are ready.
+    ready_check: bool,
+    /// Seated players who have sent `ready`.
+    ready: HashSet<String>,
+    /// Games won by each player in the current match.
+    score: HashMap<String, u32>,
+    status: GameStatus,
+    winner: Option<char>,
+    end_reason: Option<GameOverReason>,
+    /// Cells of the completed line, empty unless the game was won on the board.
+    winning_line: Vec<[usize; 2]>,
+    /// When the room was created, in Unix seconds.
+    created_at: u64,
+    /// How long the last round took, once it is over.
+    duration_secs: Option<u64>,
+    /// Bumped whenever a new turn starts so stale timeouts can be ignored.
+    #[serde(skip)]
+    turn_seq: u64,
+    /// Which wait for an opponent is current, so stale join timeouts can be ignored.
+    #[serde(skip)]
+    join_generation: u64,
+    #[serde(rename = "turn_remaining_ms", serialize_with = "serialize_remaining")]
+    turn_deadline: Option<Instant>,
+    rematch_requests: HashSet<String>,
+    /// Player who has offered a draw that the opponent has not answered yet.
+    #[serde(rename = "draw_offered_by")]
+    draw_offer: Option<String>,
+    #[serde(skip)]
+    moves: Vec<MoveRecord>,
+    /// How long each move in `moves` was thought over.
+    #[serde(skip)]
+    move_times: Vec<Duration>,
+    /// Think time per player, filled in by `snapshot` once the game is over.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    think_times: Option<HashMap<String, ThinkTime>>,
+    /// Sequence number of the last chat message relayed in this room.
+    #[serde(skip)]
+    chat_seq: u64,
+    /// Join code of a private game; never sent to clients.
+    #[serde(skip)]
+    code: Option<String>,
+    #[serde(skip)]
+    opened_at: Instant,
+    /// When the current round got its second player.
+    #[serde(skip)]
+    round_started: Option<Instant>,
+    #[serde(skip)]
+    turn_started: Option<Instant>,
+    #[serde(skip)]
+    finished_at: Option<Instant>,
 }
 
-#[derive(Deserialize)]
-struct WsMessage {
-    message_type: String,
-    player: Option<String>,
-    game_id: Option<String>,
-    r#move: Option<Vec<usize>>,
+/// Machine-readable reason a move was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    CellOccupied,
+    OutOfBounds,
+    /// The position has no coordinates at all.
+    EmptyPosition,
+    /// The position has fewer coordinates than the variant needs.
+    PositionTooShort,
+    /// The position has more coordinates than the variant needs.
+    PositionTooLong,
+    ColumnFull,
+    /// The message could not be parsed.
+    BadJson,
+    NotYourTurn,
 }
 
-pub struct GameServer {
-    sessions: HashMap<String, UnboundedSender<ClientMessage>>,
-    games: HashMap<String, Room>,
-}
+impl ErrorCode {
+    /// Why a position with `len` coordinates does not fit one that needs `expected`.
+    fn for_shape(len: usize, expected: usize) -> ErrorCode {
+        match len {
+            0 => ErrorCode::EmptyPosition,
+            len if len < expected => ErrorCode::PositionTooShort,
+            _ => ErrorCode::PositionTooLong,
+        }
+    }
 
-impl Default for GameServer {
-    fn default() -> Self {
-        GameServer {
-            sessions: HashMap::new(),
-            games: HashMap::new(),
+    fn message(self) -> &'static str {
+        match self {
+            ErrorCode::CellOccupied => "cell occupied",
+            ErrorCode::OutOfBounds => "out of bounds",
+            ErrorCode::EmptyPosition => "empty position",
+            ErrorCode::PositionTooShort => "position is missing a coordinate",
+            ErrorCode::PositionTooLong => "position has too many coordinates",
+            ErrorCode::ColumnFull => "column full",
+            ErrorCode::BadJson => "invalid message",
+            ErrorCode::NotYourTurn => "Not your turn",
         }
     }
 }
 
-impl GameServer {
-    fn send_to_player(&self, player_id: &str, msg: ClientMessage) {
-        if let Some(addr) = self.sessions.get(player_id) {
-            let mut addr = addr.clone();
-            rt::spawn(async move {
-                let _ = addr.send(msg).await;
-            });
+/// Why the server closes a connection. Each cause has its own close code
+/// so clients can tell them apart: a standard code where one fits, 4000
+/// and up for reasons specific to this server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCause {
+    Shutdown,
+    /// The game server task is gone.
+    Internal,
+    FrameTooLarge,
+    /// A message was not valid UTF-8.
+    InvalidPayload,
+    HeartbeatTimeout,
+    /// The player sat in a game without acting for too long.
+    Idle,
+    /// An admin closed the game the connection was in.
+    ClosedByAdmin,
+    /// The client broke a rule of the protocol, such as acting as another
+    /// player or sending moves too fast.
+    PolicyViolation,
+}
+
+impl CloseCause {
+    fn code(self) -> u16 {
+        match self {
+            CloseCause::Shutdown => 1001,
+            CloseCause::InvalidPayload => 1007,
+            CloseCause::PolicyViolation => 1008,
+            CloseCause::FrameTooLarge => 1009,
+            CloseCause::Internal => 1011,
+            CloseCause::HeartbeatTimeout => 4000,
+            CloseCause::Idle => 4001,
+            CloseCause::ClosedByAdmin => 4002,
         }
     }
 
-    fn notify_game_state(&self, game: &Room) {
-        for player_id in &game.players {
-            self.send_to_player(
-                player_id,
-                ClientMessage::GameState(game.clone())
-            );
-            self.send_to_player(
-                player_id,
-                ClientMessage::YourTurn(game.x_turn == (player_id == "X"))
-            );
+    fn description(self) -> &'static str {
+        match self {
+            CloseCause::Shutdown => "server shutting down",
+            CloseCause::Internal => "internal error",
+            CloseCause::FrameTooLarge => "frame too large",
+            CloseCause::InvalidPayload => "message is not valid UTF-8",
+            CloseCause::HeartbeatTimeout => "heartbeat timed out",
+            CloseCause::Idle => "kicked for inactivity",
+            CloseCause::ClosedByAdmin => "game closed by admin",
+            CloseCause::PolicyViolation => "policy violation",
         }
     }
 
-    fn handle(&mut self, msg: ServerMessage) {
-        match msg {
-            ServerMessage::Connect { id, mut sender } => {
-                println!("Player {} connected", id);
-                self.sessions.insert(id.clone(), sender.clone());
-                let _ = sender.send(ClientMessage::Id(id));
-            }
+    fn reason(self) -> web::ws::CloseReason {
+        web::ws::CloseReason {
+            code: self.code().into(),
+            description: Some(self.description().into()),
+        }
+    }
+}
 
-            ServerMessage::Disconnect(id) => {
-                println!("Player {} disconnected", id);
-                self.sessions.remove(&id);
-                
-                // Remove player from their game
-                for game in self.games.values_mut() {
-                    game.players.remove(&id);
-                }
-            }
+/// Who a chat message was meant for, which follows from the sender's role.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatChannel {
+    /// From a player, seen by everyone in the game.
+    Players,
+    /// From a spectator, seen only by the other spectators.
+    Spectator,
+}
 
-            ServerMessage::JoinGame { player_id, game_id } => {
-                let game = self.games.entry(game_id.clone())
-                    .or_insert_with(|| Room {
-                        game_id: game_id.clone(),
-                        players: HashSet::new(),
-                        board: vec![vec!["_".to_string(); 3]; 3],
-                        x_turn: true,
-                    });
+/// Optional protocol features a client can ask for, on connect with
+/// `?capabilities=` or later with a `hello` message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    /// `MoveApplied` deltas after each move instead of the full state.
+    Delta,
+    /// Chat messages relayed from the rest of the game.
+    Chat,
+}
 
-                if game.players.len() < 2 {
-                    game.players.insert(player_id);
-                    let game_state = game.clone();
-                    self.notify_game_state(&game_state);
-                } else {
-                    self.send_to_player(&player_id, ClientMessage::Error("Game is full".into()));
-                }
-            }
+impl Feature {
+    /// Unknown names are ignored so newer clients can talk to older servers.
+    fn parse(name: &str) -> Option<Feature> {
+        match name.trim() {
+            "delta" | "move_delta" => Some(Feature::Delta),
+            "chat" => Some(Feature::Chat),
+            _ => None,
+        }
+    }
+}
 
-            ServerMessage::MakeMove { player_id, game_id, position } => {
-                if let Some(game) = self.games.get_mut(&game_id) {
-                    if !game.players.contains(&player_id) {
-                        self.send_to_player(&player_id, ClientMessage::Error("Not in game".into()));
-                        return;
-                    }
+/// The rule set a game is played under, fixed when the room is created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Variant {
+    #[default]
+    Standard,
+    /// Completing a line loses.
+    Misere,
+    /// Moves name a column and the piece drops to the lowest empty cell in it.
+    ConnectFour,
+}
 
-                    let is_x = player_id == "X";
-                    if game.x_turn != is_x {
-                        self.send_to_player(&player_id, ClientMessage::Error("Not your turn".into()));
-                        return;
-                    }
+impl Variant {
+    /// Board size and win length used when a game does not ask for its own.
+    fn default_rules(self) -> (usize, usize) {
+        match self {
+            Variant::Standard | Variant::Misere => (DEFAULT_BOARD_SIZE, DEFAULT_WIN_LENGTH),
+            Variant::ConnectFour => (7, 4),
+        }
+    }
 
-                    let [row, col] = position.as_slice() else {
-                        self.send_to_player(&player_id, ClientMessage::Error("Invalid move".into()));
-                        return;
-                    };
+    /// Who wins when `symbol` completes a line.
+    fn winner(self, symbol: char) -> char {
+        match (self, symbol) {
+            (Variant::Standard | Variant::ConnectFour, symbol) => symbol,
+            (Variant::Misere, 'X') => 'O',
+            (Variant::Misere, _) => 'X',
+        }
+    }
+}
 
-                    if game.board[*row][*col] != "_" {
-                        self.send_to_player(&player_id, ClientMessage::Error("Invalid move".into()));
-                        return;
-                    }
+/// What happens when a player lets their turn clock run out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeoutPolicy {
+    /// They lose the game.
+    #[default]
+    Forfeit,
+    /// A random legal move is played for them and the game goes on.
+    AutoMove,
+}
 
-                    game.board[*row][*col] = if is_x { "X" } else { "O" }.to_string();
-                    game.x_turn = !game.x_turn;
-                    let game_state = game.clone();
-                    self.notify_game_state(&game_state);
-                }
-            }
+/// Where a game is in its life: waiting for a second player, being
+/// played, or over until a rematch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GameStatus {
+    Waiting,
+    InProgress,
+    Finished,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GameOverReason {
+    Win,
+    Draw,
+    Timeout,
+    Resignation,
+    Agreement,
+    /// The other player disconnected and did not come back in time.
+    OpponentForfeit,
+}
+
+impl GameOverReason {
+    const ALL: [GameOverReason; 6] = [
+        GameOverReason::Win,
+        GameOverReason::Draw,
+        GameOverReason::Timeout,
+        GameOverReason::Resignation,
+        GameOverReason::Agreement,
+        GameOverReason::OpponentForfeit,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            GameOverReason::Win => "win",
+            GameOverReason::Draw => "draw",
+            GameOverReason::Timeout => "timeout",
+            GameOverReason::Resignation => "resignation",
+            GameOverReason::Agreement => "agreement",
+            GameOverReason::OpponentForfeit => "opponent_forfeit",
         }
     }
 }
 
-pub fn start_game_server() -> UnboundedSender<ServerMessage> {
-    let (tx, mut rx) = mpsc::unbounded();
+/// Running totals since startup, exported on `/metrics`.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    games_created: u64,
+    /// Finished games keyed by result label.
+    games_finished: BTreeMap<&'static str, u64>,
+    moves: u64,
+    /// Times the server's mailbox filled past 90% of its capacity.
+    queue_near_full: u64,
+}
 
-    rt::spawn(async move {
-        let mut server = GameServer::default();
-        while let Some(msg) = rx.next().await {
-            server.handle(msg);
-        }
-    });
+impl Metrics {
+    fn record_finish(&mut self, reason: GameOverReason) {
+        *self.games_finished.entry(reason.as_str()).or_default() += 1;
+    }
+}
 
-    tx
+/// Where and when a session connected from, for moderation.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SessionInfo {
+    peer: Option<IpAddr>,
+    /// Unix seconds.
+    connected_at: u64,
 }
 
-#[derive(Clone)]
-struct WsState {
-    tx: Arc<tokio::sync::Mutex<UnboundedSender<ServerMessage>>>,
-    hb: Instant,
+/// A member of a game as admins see it. The session details are missing
+/// while the member is disconnected.
+#[derive(Debug, Clone, Serialize)]
+pub struct AdminMember {
+    player_id: String,
+    role: Role,
+    #[serde(flatten)]
+    session: Option<SessionInfo>,
 }
 
-async fn ws_service(
-    sink: web::ws::WsSink,
-    mut server: UnboundedSender<ServerMessage>,
-) -> Result<impl Service<web::ws::Frame, Response = Option<web::ws::Message>, Error = std::io::Error>, web::Error> {
-    let (client_tx, mut client_rx) = mpsc::unbounded();
-    let player_id = uuid::Uuid::new_v4().to_string();
+/// What `GET /admin/games/{id}` returns.
+#[derive(Debug, Clone, Serialize)]
+pub struct AdminGameView {
+    game: Room,
+    members: Vec<AdminMember>,
+}
 
-    // Connect player to server
-    server.send(ServerMessage::Connect {
-        id: player_id.clone(),
-        sender: client_tx,
-    }).await.unwrap();
+/// A point-in-time view of the server, served as JSON on `/stats` and
+/// together with the running totals on `/metrics`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerStats {
+    active_games: usize,
+    active_sessions: usize,
+    games_in_progress: usize,
+    games_by_status: BTreeMap<GameStatus, usize>,
+    /// Commands waiting in the server's mailbox.
+    queue_depth: usize,
+    #[serde(skip)]
+    metrics: Metrics,
+}
 
-    // Spawn task to forward messages from server to websocket
-    let sink2 = sink.clone();
-    rt::spawn(async move {
-        while let Some(msg) = client_rx.next().await {
-            match msg {
-                ClientMessage::GameState(state) => {
-                    let _ = sink2.send(web::ws::Message::Text(
-                        serde_json::to_string(&state).unwrap().into()
-                    )).await;
-                }
-                ClientMessage::YourTurn(is_turn) => {
-                    let _ = sink2.send(web::ws::Message::Text(
-                        format!("{{\"type\":\"turn\",\"is_turn\":{}}}", is_turn).into()
-                    )).await;
-                }
-                _ => {}
-            }
+fn serialize_remaining<S: serde::Serializer>(deadline: &Option<Instant>, serializer: S) -> Result<S::Ok, S::Error> {
+    match deadline {
+        Some(deadline) => {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            serializer.serialize_some(&(remaining.as_millis() as u64))
         }
-    });
+        None => serializer.serialize_none(),
+    }
+}
 
-    // Handler service for incoming websocket frames
-    let (tx, _) = futures::channel::mpsc::unbounded();
-    let tx_mutex = Arc::new(tokio::sync::Mutex::new(tx));
-    let state = WsState { 
-        tx: tx_mutex,
-        hb: Instant::now(),
-    };
-    let state = state.clone();
-    let tx = state.tx.clone();
-    let hb = Arc::new(tokio::sync::Mutex::new(state.hb));
-    let tx_clone = tx.clone();
-    let hb_clone = hb.clone();
-    let service = fn_service(move |frame| {
-        let tx = tx_clone.clone();
-        let hb = hb_clone.clone();
-        async move {
-            match frame {
-                web::ws::Frame::Ping(msg) => Ok(Some(web::ws::Message::Pong(msg))),
-                web::ws::Frame::Pong(_) => {
-                    *hb.lock().await = Instant::now();
-                    Ok(None)
-                }
-                web::ws::Frame::Text(text) => {
-                    let text_str = String::from_utf8(Vec::from(text.as_ref())).unwrap();
-                    if let Ok(msg) = serde_json::from_str::<WsMessage>(&text_str) {
-                        let item = match msg.message_type.as_str() {
-                            "join" => {
-                                if let (Some(player), Some(game_id)) = (msg.player, msg.game_id) {
-                                    tx.lock().await.unbounded_send(ServerMessage::JoinGame {
-                                        player_id: player,
-                                        game_id,
-                                    }).unwrap();
-                                    None
-                                } else {
-                                    None
-                                }
-                            }
-                            "move" => {
-                                if let (Some(player), Some(game_id), Some(mv)) = (msg.player, msg.game_id, msg.r#move) {
-                                    tx.lock().await.unbounded_send(ServerMessage::MakeMove {
-                                        player_id: player,
-                                        game_id,
-                                        position: mv,
-                                    }).unwrap();
-                                    None
-                                } else {
-                                    None
-                                }
-                            }
-                            _ => None,
-                        };
-                        Ok(item)
-                    } else {
-                        Ok(None)
-                    }
-                }
-                web::ws::Frame::Close(reason) => Ok(Some(web::ws::Message::Close(reason))),
-                _ => Ok(None),
-            }
+/// How long a player took over their moves in one game.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ThinkTime {
+    moves: usize,
+    avg_ms: u64,
+    max_ms: u64,
+}
+
+/// What the lobby shows about a game.
+#[derive(Debug, Clone, Serialize)]
+pub struct GameSummary {
+    game_id: String,
+    players: usize,
+    spectators: usize,
+    size: usize,
+    win_length: usize,
+    rounds: usize,
+    variant: Variant,
+    status: GameStatus,
+    created_at: u64,
+}
+
+/// Order of the games list.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GameSort {
+    /// Oldest first.
+    #[default]
+    CreatedAt,
+    /// Fullest first, oldest first among equals.
+    Players,
+}
+
+/// One page of the games list, with how many games matched in all.
+#[derive(Debug, Clone, Serialize)]
+pub struct GamePage {
+    total: usize,
+    games: Vec<GameSummary>,
+}
+
+const INITIAL_RATING: i32 = 1200;
+/// Most a rating can move in a single game.
+const RATING_K: f64 = 32.0;
+
+/// A named player's rating and results across every rated game.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlayerRecord {
+    rating: i32,
+    wins: u32,
+    losses: u32,
+    draws: u32,
+}
+
+impl Default for PlayerRecord {
+    fn default() -> Self {
+        PlayerRecord { rating: INITIAL_RATING, wins: 0, losses: 0, draws: 0 }
+    }
+}
+
+impl PlayerRecord {
+    /// The record after a game against an opponent rated `opponent`,
+    /// scoring 1 for a win, 0.5 for a draw and 0 for a loss.
+    fn after_game(&self, opponent: i32, score: f64) -> PlayerRecord {
+        let expected = 1.0 / (1.0 + 10f64.powf((opponent - self.rating) as f64 / 400.0));
+        let mut record = PlayerRecord {
+            rating: self.rating + (RATING_K * (score - expected)).round() as i32,
+            ..self.clone()
+        };
+        match score {
+            score if score > 0.5 => record.wins += 1,
+            score if score < 0.5 => record.losses += 1,
+            _ => record.draws += 1,
         }
-    });
+        record
+    }
+}
 
-    let (tx, _) = oneshot::channel();
-    let on_shutdown = fn_shutdown(move || {
-        let _ = tx.send(());
-    });
+/// How far apart two ratings may be for a quick match, at first.
+const MATCH_WINDOW: u32 = 100;
+/// How much the window widens for each `MATCH_WINDOW_STEP` waited.
+const MATCH_WINDOW_GROWTH: u32 = 50;
+const MATCH_WINDOW_STEP: Duration = Duration::from_secs(5);
+/// After this long a waiting player is matched with anyone.
+const MATCH_ANYONE_AFTER: Duration = Duration::from_secs(30);
+/// How often waiting players are checked for a match as their windows grow.
+const MATCHMAKING_INTERVAL: Duration = Duration::from_secs(1);
 
-    Ok(chain(service).and_then(on_shutdown))
+fn match_window(waited: Duration) -> u32 {
+    if waited >= MATCH_ANYONE_AFTER {
+        return u32::MAX;
+    }
+    let steps = (waited.as_secs() / MATCH_WINDOW_STEP.as_secs()) as u32;
+    MATCH_WINDOW + MATCH_WINDOW_GROWTH * steps
 }
 
+/// A player waiting in the quick match queue.
+#[derive(Debug, Clone)]
+struct QueuedPlayer {
+    player_id: String,
+    name: String,
+    /// Whether the name was chosen, so the game can be rated.
+    named: bool,
+    rating: i32,
+    since: Instant,
+}
 
-async fn ws_index(
-    req: web::HttpRequest,
-    server: web::types::State<UnboundedSender<ServerMessage>>,
-) -> Result<web::HttpResponse, web::Error> {
-    web::ws::start(
-        req,
-        fn_factory_with_config(move |sink| ws_service(sink, server.get_ref().clone())),
-    ).await
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MoveRecord {
+    player_id: String,
+    row: usize,
+    col: usize,
+    /// Milliseconds since the Unix epoch.
+    timestamp: u64,
 }
 
-#[ntex::main]
-async fn main() -> std::io::Result<()> {
-    let server = start_game_server();
-    
-    web::HttpServer::new(move || {
-        web::App::new()
-            .state(server.clone())
-            .wrap(web::middleware::Logger::default())
-            .service(web::resource("/ws/").route(web::get().to(ws_index)))
-    })
-        .bind(("127.0.0.1", 8080))?
-        .run()
-        .await
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn unix_secs() -> u64 {
+    unix_millis() / 1000
+}
+
+fn serialize_len<S: serde::Serializer>(set: &HashSet<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(set.len() as u64)
+}
+
+/// Version of the message schema sent and accepted over the websocket.
+/// Bump it whenever a message gains, loses or changes a field.
+const PROTOCOL_VERSION: u32 = 11;
+const SERVER_VERSION: &str = env!("CARGO_PKG_VERSION");
+const MAX_CHAT_LENGTH: usize = 500;
+const MAX_ANNOUNCEMENT_LENGTH: usize = 500;
+const DEFAULT_BOARD_SIZE: usize = 3;
+const MIN_BOARD_SIZE: usize = 3;
+const MAX_BOARD_SIZE: usize = 19;
+const DEFAULT_WIN_LENGTH: usize = 3;
+const MIN_WIN_LENGTH: usize = 3;
+const MAX_ROUNDS: usize = 9;
+const MAX_NAME_LENGTH: usize = 32;
+const DEFAULT_REPLAY_DELAY: Duration = Duration::from_millis(500);
+const MAX_REPLAY_DELAY: Duration = Duration::from_secs(5);
+/// Seat id of the server-controlled opponent; never handed to a client.
+const BOT_PLAYER_ID: &str = "bot";
+
+fn validate_board_size(size: usize) -> Result<usize, String> {
+    if (MIN_BOARD_SIZE..=MAX_BOARD_SIZE).contains(&size) {
+        Ok(size)
+    } else {
+        Err(format!("Board size must be between {} and {}", MIN_BOARD_SIZE, MAX_BOARD_SIZE))
+    }
+}
+
+fn validate_win_length(win_length: usize, size: usize) -> Result<usize, String> {
+    if (MIN_WIN_LENGTH..=size).contains(&win_length) {
+        Ok(win_length)
+    } else {
+        Err(format!("Win length must be between {} and the board size", MIN_WIN_LENGTH))
+    }
+}
+
+fn validate_rounds(rounds: usize) -> Result<usize, String> {
+    // An odd length means someone always ends up with the majority
+    if (1..=MAX_ROUNDS).contains(&rounds) && rounds % 2 == 1 {
+        Ok(rounds)
+    } else {
+        Err(format!("Rounds must be an odd number between 1 and {}", MAX_ROUNDS))
+    }
+}
+
+const MAX_ID_LENGTH: usize = 64;
+
+/// Whether a client-supplied game id is 1 to 64 letters, digits and
+/// dashes. Server-generated UUIDs always pass.
+fn is_valid_id(id: &str) -> bool {
+    (1..=MAX_ID_LENGTH).contains(&id.len())
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+const MIN_CODE_LENGTH: usize = 4;
+const MAX_CODE_LENGTH: usize = 16;
+
+fn validate_code(code: &str) -> Result<String, String> {
+    let valid = (MIN_CODE_LENGTH..=MAX_CODE_LENGTH).contains(&code.len())
+        && code.chars().all(|c| c.is_ascii_alphanumeric());
+    if valid {
+        Ok(code.to_string())
+    } else {
+        Err(format!("Join codes must be {} to {} letters or digits", MIN_CODE_LENGTH, MAX_CODE_LENGTH))
+    }
+}
+
+/// Cleans up a requested display name, falling back to a short form of
+/// the player id when none is given.
+fn validate_name(name: Option<&str>, player_id: &str) -> Result<String, String> {
+    let name: String = name.unwrap_or_default().chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim();
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(format!("Names are limited to {} characters", MAX_NAME_LENGTH));
+    }
+    if !name.chars().all(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_')) {
+        return Err("Names may only use letters, digits, spaces, dashes and underscores".into());
+    }
+    if name.is_empty() {
+        return Ok(default_name(player_id));
+    }
+    Ok(name.to_string())
+}
+
+/// A short form of the player id, for players who gave no name.
+fn default_name(player_id: &str) -> String {
+    player_id.chars().take(8).collect()
+}
+
+/// Resolves the requested board size, win length and match length, applying
+/// the variant's defaults.
+fn validate_rules(
+    variant: Variant,
+    size: Option<usize>,
+    win_length: Option<usize>,
+    rounds: Option<usize>,
+) -> Result<(usize, usize, usize), String> {
+    let (default_size, default_win_length) = variant.default_rules();
+    let size = validate_board_size(size.unwrap_or(default_size))?;
+    let win_length = validate_win_length(win_length.unwrap_or(default_win_length), size)?;
+    let rounds = validate_rounds(rounds.unwrap_or(1))?;
+    Ok((size, win_length, rounds))
+}
+
+/// Steps along a row, a column and both diagonals.
+const DIRECTIONS: [(isize, isize); 4] = [(0, 1), (1, 0), (1, 1), (1, -1)];
+
+impl Room {
+    fn new(game_id: String, size: usize, win_length: usize, rounds: usize) -> Self {
+        Room {
+            game_id,
+            players: HashSet::new(),
+            symbols: HashMap::new(),
+            names: HashMap::new(),
+            named: HashSet::new(),
+            spectators: HashSet::new(),
+            board: vec![vec![Cell::Empty; size]; size],
+            x_turn: true,
+            started_by: None,
+            current_player: None,
+            win_length,
+            rounds,
+            variant: Variant::Standard,
+            timeout_policy: TimeoutPolicy::Forfeit,
+            ready_check: false,
+            ready: HashSet::new(),
+            score: HashMap::new(),
+            status: GameStatus::Waiting,
+            winner: None,
+            end_reason: None,
+            winning_line: Vec::new(),
+            turn_seq: 0,
+            join_generation: 0,
+            turn_deadline: None,
+            rematch_requests: HashSet::new(),
+            draw_offer: None,
+            moves: Vec::new(),
+            move_times: Vec::new(),
+            think_times: None,
+            chat_seq: 0,
+            code: None,
+            created_at: unix_secs(),
+            duration_secs: None,
+            opened_at: Instant::now(),
+            round_started: None,
+            turn_started: None,
+            finished_at: None,
+        }
+    }
+
+    /// Clears the board for a new round with the same players.
+    fn reset(&mut self) {
+        let size = self.board.len();
+        self.board = vec![vec![Cell::Empty; size]; size];
+        self.x_turn = true;
+        self.status = GameStatus::Waiting;
+        self.started_by = None;
+        self.winner = None;
+        self.end_reason = None;
+        self.winning_line.clear();
+        self.finished_at = None;
+        self.duration_secs = None;
+        self.round_started = None;
+        self.rematch_requests.clear();
+        self.draw_offer = None;
+        self.moves.clear();
+        self.move_times.clear();
+        // Every game of a ready-check room waits for both players again
+        self.ready.clear();
+        if self.players.len() == 2 && !self.ready_check {
+            self.begin();
+        }
+    }
+
+    fn finish(&mut self, winner: Option<char>, reason: GameOverReason) {
+        self.status = GameStatus::Finished;
+        self.draw_offer = None;
+        self.winner = winner;
+        self.end_reason = Some(reason);
+        self.finished_at = Some(Instant::now());
+        self.duration_secs = self.round_started.map(|started| started.elapsed().as_secs());
+        self.stop_turn_clock();
+        if let Some(symbol) = winner {
+            if let Some(player_id) = self.symbols.iter().find(|(_, s)| **s == symbol).map(|(id, _)| id) {
+                *self.score.entry(player_id.clone()).or_default() += 1;
+            }
+        }
+        info!(game_id = %self.game_id, ?winner, ?reason, "game over");
+    }
+
+    /// Takes a player out of their seat, or off the spectator list, and
+    /// returns whoever had asked for a rematch that can no longer happen.
+    fn remove_member(&mut self, id: &str) -> Vec<String> {
+        if !self.players.remove(id) {
+            self.spectators.remove(id);
+            return Vec::new();
+        }
+        self.stop_turn_clock();
+        if self.draw_offer.as_deref() == Some(id) {
+            self.draw_offer = None;
+        }
+        let mut cancelled = Vec::new();
+        if !self.rematch_requests.is_empty() {
+            // A pending rematch can no longer happen without the opponent
+            self.rematch_requests.remove(id);
+            cancelled.extend(self.rematch_requests.drain());
+        }
+        self.symbols.remove(id);
+        self.names.remove(id);
+        self.named.remove(id);
+        self.ready.remove(id);
+        // The seat is open again for someone else to finish the game
+        if self.status == GameStatus::InProgress {
+            self.status = GameStatus::Waiting;
+        }
+        // The bot has nobody left to play against
+        if self.players.len() == 1 && self.players.contains(BOT_PLAYER_ID) {
+            self.players.clear();
+        }
+        cancelled
+    }
+
+    /// Whether the room has a free seat and no round has been played in it yet.
+    fn awaits_opponent(&self) -> bool {
+        self.status == GameStatus::Waiting && self.players.len() < 2 && self.round_started.is_none()
+    }
+
+    /// Whether the game is held back waiting on this seated player's `ready`.
+    fn awaits_ready(&self, player_id: &str) -> bool {
+        self.ready_check
+            && self.status == GameStatus::Waiting
+            && self.players.contains(player_id)
+            && !self.ready.contains(player_id)
+    }
+
+    /// The player who has won a majority of the match's games, if any.
+    fn match_winner(&self) -> Option<String> {
+        let needed = (self.rounds / 2 + 1) as u32;
+        self.score.iter()
+            .find(|(_, wins)| **wins >= needed)
+            .map(|(player_id, _)| player_id.clone())
+    }
+
+    /// Starts play once both seats are filled. X always moves first.
+    fn begin(&mut self) {
+        self.status = GameStatus::InProgress;
+        self.round_started = Some(Instant::now());
+        self.started_by = self.symbols.iter().find(|(_, s)| **s == 'X').map(|(id, _)| id.clone());
+    }
+
+    /// Resets the board with the symbols swapped, so whoever did not move
+    /// first last round plays X and starts this one.
+    fn next_round(&mut self) {
+        let previous = self.started_by.take();
+        for (player_id, symbol) in self.symbols.iter_mut() {
+            *symbol = match &previous {
+                Some(starter) => if player_id == starter { 'O' } else { 'X' },
+                None => if *symbol == 'X' { 'O' } else { 'X' },
+            };
+        }
+        self.reset();
+    }
+
+    /// Starts the clock for whoever is to move next. Any timeout still
+    /// pending for an earlier turn is invalidated by bumping `turn_seq`.
+    fn start_turn_clock(&mut self, server: &Option<ServerSender>, timeout: Duration) {
+        self.turn_seq += 1;
+        self.turn_started = Some(Instant::now());
+        self.turn_deadline = Some(Instant::now() + timeout);
+        if let Some(server) = server {
+            let server = server.clone();
+            let game_id = self.game_id.clone();
+            let expected_turn = self.turn_seq;
+            rt::spawn(async move {
+                time::sleep(timeout).await;
+                let _ = server.send(ServerMessage::TurnTimeout { game_id, expected_turn }).await;
+            });
+        }
+    }
+
+    fn stop_turn_clock(&mut self) {
+        self.turn_seq += 1;
+        self.turn_deadline = None;
+    }
+
+    /// Returns the winning symbol and the coordinates of its line.
+    fn check_winner(&self) -> Option<(char, Vec<[usize; 2]>)> {
+        // Slide a window of `win_length` cells from every occupied cell
+        // rightwards, downwards and along both diagonals
+        for (r, row) in self.board.iter().enumerate() {
+            for (c, &cell) in row.iter().enumerate() {
+                let Some(symbol) = cell.symbol() else {
+                    continue;
+                };
+                for (dr, dc) in DIRECTIONS {
+                    let complete = (1..self.win_length as isize).all(|i| {
+                        self.cell_at(r as isize + dr * i, c as isize + dc * i) == Some(cell)
+                    });
+                    if complete {
+                        let line = (0..self.win_length as isize)
+                            .map(|i| [(r as isize + dr * i) as usize, (c as isize + dc * i) as usize])
+                            .collect();
+                        return Some((symbol, line));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Whether every possible line already holds both symbols, so neither
+    /// player can win however the remaining cells are filled.
+    fn is_draw_forced(&self) -> bool {
+        for r in 0..self.board.len() as isize {
+            for c in 0..self.board.len() as isize {
+                for (dr, dc) in DIRECTIONS {
+                    let window: Option<Vec<Cell>> = (0..self.win_length as isize)
+                        .map(|i| self.cell_at(r + dr * i, c + dc * i))
+                        .collect();
+                    // Windows running off the board are not lines
+                    let Some(window) = window else {
+                        continue;
+                    };
+                    if !(window.contains(&Cell::X) && window.contains(&Cell::O)) {
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Works out which empty cell a move lands in: the one named, or for
+    /// Connect Four the lowest free cell of the named column.
+    fn target_cell(&self, position: &[usize]) -> Result<[usize; 2], ErrorCode> {
+        let size = self.board.len();
+        if self.variant == Variant::ConnectFour {
+            let &[col] = position else {
+                return Err(ErrorCode::for_shape(position.len(), 1));
+            };
+            if col >= size {
+                return Err(ErrorCode::OutOfBounds);
+            }
+            return (0..size)
+                .rev()
+                .find(|&row| self.board[row][col] == Cell::Empty)
+                .map(|row| [row, col])
+                .ok_or(ErrorCode::ColumnFull);
+        }
+
+        let &[row, col] = position else {
+            return Err(ErrorCode::for_shape(position.len(), 2));
+        };
+        // Indexing out of range would panic the server task and take down every game
+        if row >= size || col >= size {
+            return Err(ErrorCode::OutOfBounds);
+        }
+        if self.board[row][col] != Cell::Empty {
+            return Err(ErrorCode::CellOccupied);
+        }
+        Ok([row, col])
+    }
+
+    fn cell_at(&self, row: isize, col: isize) -> Option<Cell> {
+        let row = usize::try_from(row).ok()?;
+        let col = usize::try_from(col).ok()?;
+        self.board.get(row)?.get(col).copied()
+    }
+
+    /// Why the game cannot take a move, resignation or draw offer right now.
+    fn not_in_progress(&self) -> Option<&'static str> {
+        match self.status {
+            GameStatus::Waiting => Some("Game not started"),
+            GameStatus::InProgress => None,
+            GameStatus::Finished => Some("Game over"),
+        }
+    }
+
+    fn current_player(&self) -> Option<String> {
+        if self.status == GameStatus::Finished {
+            return None;
+        }
+        let symbol = if self.x_turn { 'X' } else { 'O' };
+        self.symbols.iter()
+            .find(|(_, s)| **s == symbol)
+            .map(|(player_id, _)| player_id.clone())
+    }
+
+    /// A copy of the room as it should be sent to clients.
+    fn snapshot(&self) -> Room {
+        Room {
+            current_player: self.current_player(),
+            think_times: (self.status == GameStatus::Finished).then(|| self.think_times()),
+            ..self.clone()
+        }
+    }
+
+    /// Average and longest think time of each player who has moved.
+    fn think_times(&self) -> HashMap<String, ThinkTime> {
+        let mut per_player: HashMap<&str, Vec<Duration>> = HashMap::new();
+        for (record, time) in self.moves.iter().zip(&self.move_times) {
+            per_player.entry(&record.player_id).or_default().push(*time);
+        }
+        per_player.into_iter()
+            .map(|(player_id, times)| {
+                let total: Duration = times.iter().sum();
+                let think = ThinkTime {
+                    moves: times.len(),
+                    avg_ms: (total / times.len() as u32).as_millis() as u64,
+                    max_ms: times.iter().max().map_or(0, |max| max.as_millis() as u64),
+                };
+                (player_id.to_string(), think)
+            })
+            .collect()
+    }
+
+    /// The game before any move and after each one, for replays. Frames
+    /// are rebuilt from the move list; the last is the game as it is now.
+    fn replay_frames(&self) -> Vec<Arc<Room>> {
+        let size = self.board.len();
+        let mut frame = Room {
+            board: vec![vec![Cell::Empty; size]; size],
+            x_turn: true,
+            status: GameStatus::InProgress,
+            winner: None,
+            end_reason: None,
+            winning_line: Vec::new(),
+            duration_secs: None,
+            turn_deadline: None,
+            rematch_requests: HashSet::new(),
+            draw_offer: None,
+            moves: Vec::new(),
+            move_times: Vec::new(),
+            ..self.clone()
+        };
+        let mut frames = Vec::with_capacity(self.moves.len() + 1);
+        for record in &self.moves {
+            frames.push(Arc::new(frame.snapshot()));
+            // Turns strictly alternate, so the symbol follows from the order
+            frame.board[record.row][record.col] = if frame.x_turn { Cell::X } else { Cell::O };
+            frame.moves.push(record.clone());
+            frame.x_turn = !frame.x_turn;
+        }
+        frames.push(Arc::new(self.snapshot()));
+        frames
+    }
+
+    fn summary(&self) -> GameSummary {
+        GameSummary {
+            game_id: self.game_id.clone(),
+            players: self.players.len(),
+            spectators: self.spectators.len(),
+            size: self.board.len(),
+            win_length: self.win_length,
+            rounds: self.rounds,
+            variant: self.variant,
+            status: self.status,
+            created_at: self.created_at,
+        }
+    }
+
+    fn is_x(&self, player_id: &str) -> bool {
+        self.symbols.get(player_id) == Some(&'X')
+    }
+
+    fn is_full(&self) -> bool {
+        self.board.iter().all(|row| row.iter().all(|cell| *cell != Cell::Empty))
+    }
+
+    fn empty_cells(&self) -> Vec<[usize; 2]> {
+        let mut cells = Vec::new();
+        for (r, row) in self.board.iter().enumerate() {
+            for (c, cell) in row.iter().enumerate() {
+                if *cell == Cell::Empty {
+                    cells.push([r, c]);
+                }
+            }
+        }
+        cells
+    }
+
+    /// A legal move chosen at random, for players whose turn ran out.
+    fn random_move(&self) -> Option<[usize; 2]> {
+        let size = self.board.len();
+        let moves: Vec<[usize; 2]> = if self.variant == Variant::ConnectFour {
+            (0..size).filter_map(|col| self.target_cell(&[col]).ok()).collect()
+        } else {
+            self.empty_cells()
+        };
+        if moves.is_empty() {
+            return None;
+        }
+        // A v4 UUID is 122 random bits, plenty for picking a cell
+        let pick = uuid::Uuid::new_v4().as_u128() % moves.len() as u128;
+        Some(moves[pick as usize])
+    }
+
+    /// Picks a move for whoever is to play. The classic board is small
+    /// enough to search exhaustively; larger ones take a winning move,
+    /// then a block, then the cell nearest the centre.
+    fn bot_move(&self) -> Option<[usize; 2]> {
+        let (me, them) = if self.x_turn { (Cell::X, Cell::O) } else { (Cell::O, Cell::X) };
+        let empty = self.empty_cells();
+        if self.board.len() == DEFAULT_BOARD_SIZE {
+            let mut board = self.clone();
+            return empty.into_iter().max_by_key(|&[r, c]| {
+                board.board[r][c] = me;
+                let score = -board.negamax(them, 1, -i32::MAX, i32::MAX);
+                board.board[r][c] = Cell::Empty;
+                score
+            });
+        }
+
+        let mut board = self.clone();
+        for cell in [me, them] {
+            for &[r, c] in &empty {
+                board.board[r][c] = cell;
+                let wins = board.check_winner().is_some();
+                board.board[r][c] = Cell::Empty;
+                if wins {
+                    return Some([r, c]);
+                }
+            }
+        }
+        let centre = self.board.len() / 2;
+        empty.into_iter().min_by_key(|&[r, c]| r.abs_diff(centre) + c.abs_diff(centre))
+    }
+
+    /// Scores the position for `to_move`, preferring quicker wins, with
+    /// alpha-beta pruning so the search never stalls the server.
+    fn negamax(&mut self, to_move: Cell, depth: i32, mut alpha: i32, beta: i32) -> i32 {
+        if self.check_winner().is_some() {
+            // The previous move completed a line
+            return depth - 10;
+        }
+        let empty = self.empty_cells();
+        if empty.is_empty() {
+            return 0;
+        }
+        let next = if to_move == Cell::X { Cell::O } else { Cell::X };
+        let mut best = -i32::MAX;
+        for [r, c] in empty {
+            self.board[r][c] = to_move;
+            best = best.max(-self.negamax(next, depth + 1, -beta, -alpha));
+            self.board[r][c] = Cell::Empty;
+            alpha = alpha.max(best);
+            if alpha >= beta {
+                break;
+            }
+        }
+        best
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cell {
+    Empty,
+    X,
+    O,
+}
+
+impl Cell {
+    fn symbol(self) -> Option<char> {
+        match self {
+            Cell::Empty => None,
+            Cell::X => Some('X'),
+            Cell::O => Some('O'),
+        }
+    }
+}
+
+/// Empty cells go over the wire as `null`, occupied ones as `"X"`/`"O"`.
+impl Serialize for Cell {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.symbol() {
+            Some(symbol) => serializer.serialize_char(symbol),
+            None => serializer.serialize_none(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Cell {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match Option::<char>::deserialize(deserializer)? {
+            None => Ok(Cell::Empty),
+            Some('X') => Ok(Cell::X),
+            Some('O') => Ok(Cell::O),
+            Some(other) => Err(serde::de::Error::custom(format!("invalid cell {:?}", other))),
+        }
+    }
+}
+
+/// A message to a websocket client, discriminated by its `"type"` field.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Outgoing {
+    Id { id: String, token: String, server_version: &'static str, protocol_version: u32 },
+    Hello { server_version: &'static str, protocol_version: u32, features: BTreeSet<Feature> },
+    GameState(Arc<Room>),
+    Turn { is_turn: bool },
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        position: Option<Vec<usize>>,
+        /// More about what went wrong, for client developers.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        detail: Option<String>,
+        /// Whose turn it is, on errors about moving out of turn.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        current_player: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        x_turn: Option<bool>,
+    },
+    GameOver {
+        winner: Option<char>,
+        reason: GameOverReason,
+        line: Vec<[usize; 2]>,
+        duration_secs: Option<u64>,
+        think_times: HashMap<String, ThinkTime>,
+    },
+    Chat { from: String, text: String, seq: u64, channel: ChatChannel },
+    DrawOffered { from: String },
+    MatchOver { winner: String, score: HashMap<String, u32> },
+    LobbyUpdate { games: Vec<GameSummary> },
+    MoveApplied { position: [usize; 2], symbol: char, x_turn: bool },
+    MoveAck { position: [usize; 2] },
+    PlayerJoined { player_id: String },
+    PlayerLeft { player_id: String },
+    Announcement { text: String },
+    Joined {
+        game_id: String,
+        role: Role,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        symbol: Option<char>,
+    },
+    GameStarted { game_id: String },
+}
+
+impl From<ClientMessage> for Outgoing {
+    fn from(msg: ClientMessage) -> Self {
+        match msg {
+            ClientMessage::Id { id, token } => Outgoing::Id {
+                id,
+                token,
+                server_version: SERVER_VERSION,
+                protocol_version: PROTOCOL_VERSION,
+            },
+            ClientMessage::GameState(game) => Outgoing::GameState(game),
+            ClientMessage::YourTurn(is_turn) => Outgoing::Turn { is_turn },
+            ClientMessage::Close(cause) => Outgoing::Error {
+                code: None,
+                message: cause.description().into(),
+                position: None,
+                detail: None,
+                current_player: None,
+                x_turn: None,
+            },
+            ClientMessage::Error(message) => Outgoing::Error {
+                code: None,
+                message,
+                position: None,
+                detail: None,
+                current_player: None,
+                x_turn: None,
+            },
+            ClientMessage::NotYourTurn { current_player, x_turn } => Outgoing::Error {
+                code: Some(ErrorCode::NotYourTurn),
+                message: ErrorCode::NotYourTurn.message().into(),
+                position: None,
+                detail: None,
+                current_player,
+                x_turn: Some(x_turn),
+            },
+            ClientMessage::InvalidMove { code, position } => Outgoing::Error {
+                code: Some(code),
+                message: code.message().into(),
+                position: Some(position),
+                detail: None,
+                current_player: None,
+                x_turn: None,
+            },
+            ClientMessage::GameOver { winner, reason, line, duration_secs, think_times } => {
+                Outgoing::GameOver { winner, reason, line, duration_secs, think_times }
+            }
+            ClientMessage::Chat { from, text, seq, channel } => Outgoing::Chat { from, text, seq, channel },
+            ClientMessage::DrawOffered { from } => Outgoing::DrawOffered { from },
+            ClientMessage::MatchOver { winner, score } => Outgoing::MatchOver { winner, score },
+            ClientMessage::LobbyUpdate(games) => Outgoing::LobbyUpdate { games },
+            ClientMessage::MoveApplied { position, symbol, x_turn } => Outgoing::MoveApplied { position, symbol, x_turn },
+            ClientMessage::MoveAck { position } => Outgoing::MoveAck { position },
+            ClientMessage::PlayerJoined { player_id } => Outgoing::PlayerJoined { player_id },
+            ClientMessage::PlayerLeft { player_id } => Outgoing::PlayerLeft { player_id },
+            ClientMessage::Announcement { text } => Outgoing::Announcement { text },
+            ClientMessage::Joined { game_id, role, symbol } => Outgoing::Joined { game_id, role, symbol },
+            ClientMessage::GameStarted { game_id } => Outgoing::GameStarted { game_id },
+        }
+    }
+}
+
+/// A move's target cell, sent either as `[row, col]` or as `{"row": .., "col": ..}`;
+/// Connect Four moves give only the column, as `[col]` or `{"col": ..}`.
+/// The array form is kept loose so a wrong length is reported as a move
+/// error saying what is wrong with it, rather than as a parse error.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Position {
+    Array(Vec<usize>),
+    Object { row: usize, col: usize },
+    Column { col: usize },
+}
+
+impl From<Position> for Vec<usize> {
+    fn from(position: Position) -> Self {
+        match position {
+            Position::Array(cells) => cells,
+            Position::Object { row, col } => vec![row, col],
+            Position::Column { col } => vec![col],
+        }
+    }
+}
+
+/// A message from a websocket client, discriminated by its `"type"` field.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Incoming {
+    Join {
+        player: String,
+        game_id: String,
+        role: Option<String>,
+        size: Option<usize>,
+        win_length: Option<usize>,
+        rounds: Option<usize>,
+        variant: Option<Variant>,
+        timeout_policy: Option<TimeoutPolicy>,
+        ready_check: Option<bool>,
+        name: Option<String>,
+        code: Option<String>,
+    },
+    JoinBot {
+        player: String,
+        size: Option<usize>,
+        win_length: Option<usize>,
+        name: Option<String>,
+        timeout_policy: Option<TimeoutPolicy>,
+    },
+    QuickMatch {
+        player: String,
+        name: Option<String>,
+    },
+    Leave {
+        player: String,
+        game_id: String,
+    },
+    Move {
+        player: String,
+        game_id: String,
+        #[serde(alias = "move")]
+        position: Position,
+    },
+    Rematch {
+        player: String,
+        game_id: String,
+    },
+    Undo {
+        player: String,
+        game_id: String,
+    },
+    Chat {
+        player: String,
+        game_id: String,
+        text: String,
+    },
+    Resign {
+        player: String,
+        game_id: String,
+    },
+    Ready {
+        player: String,
+        game_id: String,
+    },
+    DrawOffer {
+        player: String,
+        game_id: String,
+    },
+    DrawAccept {
+        player: String,
+        game_id: String,
+    },
+    DrawDecline {
+        player: String,
+        game_id: String,
+    },
+    SubscribeLobby {
+        player: String,
+    },
+    UnsubscribeLobby {
+        player: String,
+    },
+    Replay {
+        player: String,
+        game_id: String,
+        delay_ms: Option<u64>,
+    },
+    /// Declares the features the client supports; answered by the connection itself.
+    Hello {
+        #[serde(default)]
+        features: Vec<String>,
+    },
+}
+
+impl Incoming {
+    /// The player id the client sent, if the message carries one.
+    fn player(&self) -> Option<&str> {
+        match self {
+            Incoming::Join { player, .. }
+            | Incoming::JoinBot { player, .. }
+            | Incoming::QuickMatch { player, .. }
+            | Incoming::Leave { player, .. }
+            | Incoming::Move { player, .. }
+            | Incoming::Rematch { player, .. }
+            | Incoming::Undo { player, .. }
+            | Incoming::Chat { player, .. }
+            | Incoming::Resign { player, .. }
+            | Incoming::Ready { player, .. }
+            | Incoming::DrawOffer { player, .. }
+            | Incoming::DrawAccept { player, .. }
+            | Incoming::DrawDecline { player, .. }
+            | Incoming::SubscribeLobby { player }
+            | Incoming::UnsubscribeLobby { player }
+            | Incoming::Replay { player, .. } => Some(player),
+            Incoming::Hello { .. } => None,
+        }
+    }
+
+    /// The command for the game server, on behalf of the connection's own
+    /// player, or `None` for messages the connection handles on its own.
+    fn into_server_message(self, player_id: String) -> Option<ServerMessage> {
+        let msg = match self {
+            Incoming::Join {
+                game_id,
+                role,
+                size,
+                win_length,
+                rounds,
+                variant,
+                timeout_policy,
+                ready_check,
+                name,
+                code,
+                ..
+            } => ServerMessage::JoinGame {
+                player_id,
+                game_id,
+                spectate: role.as_deref() == Some("spectator"),
+                size,
+                win_length,
+                rounds,
+                variant,
+                timeout_policy,
+                ready_check,
+                name,
+                code,
+            },
+            Incoming::JoinBot { size, win_length, name, timeout_policy, .. } => ServerMessage::JoinBot {
+                player_id,
+                size,
+                win_length,
+                name,
+                timeout_policy,
+            },
+            Incoming::QuickMatch { name, .. } => ServerMessage::QuickMatch { player_id, name },
+            Incoming::Leave { game_id, .. } => ServerMessage::LeaveGame { player_id, game_id },
+            Incoming::Move { game_id, position, .. } => ServerMessage::MakeMove {
+                player_id,
+                game_id,
+                position: position.into(),
+            },
+            Incoming::Rematch { game_id, .. } => ServerMessage::Rematch { player_id, game_id },
+            Incoming::Undo { game_id, .. } => ServerMessage::Undo { player_id, game_id },
+            Incoming::Chat { game_id, text, .. } => ServerMessage::Chat { player_id, game_id, text },
+            Incoming::Resign { game_id, .. } => ServerMessage::Resign { player_id, game_id },
+            Incoming::Ready { game_id, .. } => ServerMessage::Ready { player_id, game_id },
+            Incoming::DrawOffer { game_id, .. } => ServerMessage::DrawOffer { player_id, game_id },
+            Incoming::DrawAccept { game_id, .. } => ServerMessage::DrawResponse {
+                player_id,
+                game_id,
+                accept: true,
+            },
+            Incoming::DrawDecline { game_id, .. } => ServerMessage::DrawResponse {
+                player_id,
+                game_id,
+                accept: false,
+            },
+            Incoming::SubscribeLobby { .. } => ServerMessage::SubscribeLobby { player_id, subscribe: true },
+            Incoming::UnsubscribeLobby { .. } => ServerMessage::SubscribeLobby { player_id, subscribe: false },
+            Incoming::Replay { game_id, delay_ms, .. } => ServerMessage::Replay { player_id, game_id, delay_ms },
+            Incoming::Hello { .. } => return None,
+        };
+        Some(msg)
+    }
+}
+
+#[derive(Default)]
+pub struct GameServer {
+    sessions: HashMap<String, UnboundedSender<ClientMessage>>,
+    games: HashMap<String, Room>,
+    /// Resume token to player id, for every player that can still reconnect.
+    tokens: HashMap<String, String>,
+    /// Disconnected players whose seats are held, keyed to the release
+    /// generation that will be honoured when their grace window ends.
+    pending_release: HashMap<String, u64>,
+    release_seq: u64,
+    /// Source of room join generations, unique across rooms.
+    join_seq: u64,
+    metrics: Metrics,
+    /// Handle back into our own mailbox, used to schedule timers.
+    tx: Option<ServerSender>,
+    config: Config,
+    store: Option<Store>,
+    audit: Option<AuditLog>,
+    /// Sessions that asked to be told whenever the list of games changes.
+    lobby: HashSet<String>,
+    /// Sessions that get a `MoveApplied` delta after each move instead of the full state.
+    move_deltas: HashSet<String>,
+    /// Players waiting for a quick match, lowest rated first.
+    match_queue: Vec<QueuedPlayer>,
+    /// The game each player last took a seat in. Entries may be stale, so
+    /// check the game is still running before relying on one.
+    player_game: HashMap<String, String>,
+    /// Backlog in the mailbox when the current message was taken off it.
+    queue_depth: usize,
+    /// When each connected player last connected or took a gameplay action.
+    last_action: HashMap<String, Instant>,
+    /// Ratings of named players, keyed by name.
+    ratings: HashMap<String, PlayerRecord>,
+    /// Where each connected session came from. Only admins get to see it.
+    session_info: HashMap<String, SessionInfo>,
+    /// Set while the server is being drained for a deploy: games already
+    /// started play out, but no new ones begin.
+    draining: bool,
+    /// Sessions watching over server-sent events. They have no token to
+    /// resume with, so nothing is held for them once they go.
+    watchers: HashSet<String>,
+}
+
+impl GameServer {
+    fn send_to_player(&self, player_id: &str, msg: ClientMessage) {
+        if let Some(addr) = self.sessions.get(player_id) {
+            let _ = addr.unbounded_send(msg);
+        }
+    }
+
+    /// Sends every member of a game its current state. One snapshot is
+    /// shared by all of them rather than copied per recipient.
+    fn notify_game_state(&self, game_id: &str) {
+        let Some(game) = self.games.get(game_id) else {
+            return;
+        };
+        let state = Arc::new(game.snapshot());
+        for member in game.players.iter().chain(&game.spectators) {
+            self.send_state(member, &state);
+        }
+    }
+
+    /// Tells every member of a game about a move: a delta for sessions
+    /// that asked for one, the full state for everyone else.
+    fn notify_move(&self, game_id: &str, position: [usize; 2], symbol: char) {
+        let Some(game) = self.games.get(game_id) else {
+            return;
+        };
+        let state = Arc::new(game.snapshot());
+        for member in game.players.iter().chain(&game.spectators) {
+            if !self.move_deltas.contains(member) {
+                self.send_state(member, &state);
+                continue;
+            }
+            self.send_to_player(member, ClientMessage::MoveApplied { position, symbol, x_turn: state.x_turn });
+            if state.players.contains(member) {
+                self.send_to_player(member, ClientMessage::YourTurn(state.x_turn == state.is_x(member)));
+            }
+            if let Some(reason) = state.end_reason {
+                self.send_to_player(
+                    member,
+                    ClientMessage::GameOver {
+                        winner: state.winner,
+                        reason,
+                        line: state.winning_line.clone(),
+                        duration_secs: state.duration_secs,
+                        think_times: state.think_times.clone().unwrap_or_default(),
+                    }
+                );
+            }
+        }
+    }
+
+    /// Closes an open room if nobody takes its second seat in time. Any
+    /// wait already running for the room is superseded.
+    fn schedule_join_timeout(&mut self, game_id: &str) {
+        let Some(game) = self.games.get_mut(game_id) else {
+            return;
+        };
+        self.join_seq += 1;
+        game.join_generation = self.join_seq;
+        let Some(server) = self.tx.clone() else {
+            return;
+        };
+        let game_id = game_id.to_string();
+        let generation = self.join_seq;
+        let timeout = self.config.join_timeout;
+        rt::spawn(async move {
+            time::sleep(timeout).await;
+            let _ = server.send(ServerMessage::JoinTimeout { game_id, generation }).await;
+        });
+    }
+
+    /// Tells a member of a game whether they play in it, and as which symbol.
+    fn send_joined(&self, game_id: &str, player_id: &str) {
+        let Some(game) = self.games.get(game_id) else {
+            return;
+        };
+        let msg = match game.symbols.get(player_id) {
+            Some(&symbol) => ClientMessage::Joined { game_id: game_id.into(), role: Role::Player, symbol: Some(symbol) },
+            None => ClientMessage::Joined { game_id: game_id.into(), role: Role::Spectator, symbol: None },
+        };
+        self.send_to_player(player_id, msg);
+    }
+
+    /// Sends one member of a game the full picture of it.
+    fn send_state(&self, member: &str, state: &Arc<Room>) {
+        self.send_to_player(member, ClientMessage::GameState(state.clone()));
+        if state.players.contains(member) {
+            self.send_to_player(member, ClientMessage::YourTurn(state.x_turn == state.is_x(member)));
+        }
+        if let Some(reason) = state.end_reason {
+            self.send_to_player(
+                member,
+                ClientMessage::GameOver {
+                    winner: state.winner,
+                    reason,
+                    line: state.winning_line.clone(),
+                    duration_secs: state.duration_secs,
+                    think_times: state.think_times.clone().unwrap_or_default(),
+                }
+            );
+        }
+    }
+
+    /// The public games, for the lobby. Private games stay unlisted.
+    fn game_summaries(&self) -> Vec<GameSummary> {
+        let mut games: Vec<GameSummary> = self.games.values()
+            .filter(|game| game.code.is_none())
+            .map(Room::summary)
+            .collect();
+        // Oldest first, so games that have waited longest are easiest to find
+        games.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.game_id.cmp(&b.game_id)));
+        games
+    }
+
+    /// Sends every lobby subscriber the current list of games.
+    fn broadcast_lobby(&self) {
+        if self.lobby.is_empty() {
+            return;
+        }
+        let games = self.game_summaries();
+        for player_id in &self.lobby {
+            self.send_to_player(player_id, ClientMessage::LobbyUpdate(games.clone()));
+        }
+    }
+
+    /// Whether the player holds a seat in a running game other than `game_id`.
+    fn in_other_game(&self, player_id: &str, game_id: &str) -> bool {
+        self.player_game.get(player_id)
+            .filter(|other| *other != game_id)
+            .and_then(|other| self.games.get(other))
+            .is_some_and(|game| game.status != GameStatus::Finished && game.players.contains(player_id))
+    }
+
+    fn at_capacity(&self) -> bool {
+        self.games.len() >= self.config.max_games
+    }
+
+    /// Frees every seat held by a player who is gone for good.
+    fn release_player(&mut self, id: &str) {
+        self.tokens.retain(|_, player_id| player_id != id);
+        self.player_game.remove(id);
+
+        // Games they were playing go to whoever is left at the board
+        let forfeited: Vec<String> = self.games.values()
+            .filter(|game| game.status == GameStatus::InProgress && game.players.contains(id))
+            .map(|game| game.game_id.clone())
+            .collect();
+        for game_id in forfeited {
+            let Some(game) = self.games.get_mut(&game_id) else {
+                continue;
+            };
+            let winner = if game.is_x(id) { 'O' } else { 'X' };
+            info!(game_id = %game_id, player_id = %id, "player forfeited by leaving");
+            game.finish(Some(winner), GameOverReason::OpponentForfeit);
+            self.metrics.record_finish(GameOverReason::OpponentForfeit);
+            self.rate_players(&game_id);
+            self.notify_game_state(&game_id);
+        }
+
+        let mut cancelled = Vec::new();
+        let mut reopened = Vec::new();
+        for game in self.games.values_mut() {
+            let seated = game.players.contains(id);
+            cancelled.extend(game.remove_member(id));
+            if seated && game.awaits_opponent() {
+                reopened.push(game.game_id.clone());
+            }
+        }
+        for player_id in cancelled {
+            self.send_to_player(&player_id, ClientMessage::Error("Rematch cancelled: opponent left".into()));
+        }
+        // Whoever is left waits for a new opponent, but not forever
+        for game_id in reopened {
+            self.schedule_join_timeout(&game_id);
+        }
+        self.broadcast_lobby();
+    }
+
+    /// Moves a match on once one of its games has ended: either the next
+    /// game starts straight away or everyone is told who took the match.
+    fn advance_match(&mut self, game_id: &str) {
+        let Some(game) = self.games.get_mut(game_id) else {
+            return;
+        };
+        if game.rounds <= 1 || game.status != GameStatus::Finished {
+            return;
+        }
+
+        if let Some(winner) = game.match_winner() {
+            info!(game_id = %game_id, winner = %winner, "match over");
+            let score = game.score.clone();
+            let members: Vec<String> = game.players.iter().chain(&game.spectators).cloned().collect();
+            for member in &members {
+                self.send_to_player(member, ClientMessage::MatchOver { winner: winner.clone(), score: score.clone() });
+            }
+            return;
+        }
+
+        if game.players.len() < 2 {
+            return;
+        }
+        game.next_round();
+        if game.status == GameStatus::InProgress {
+            game.start_turn_clock(&self.tx, self.config.turn_timeout);
+        }
+        self.notify_game_state(game_id);
+        self.schedule_bot_move(game_id);
+    }
+
+    /// Drops a game whose handler panicked, since its state can no longer
+    /// be trusted, and tells everyone in it. Other games carry on.
+    fn quarantine(&mut self, game_id: Option<&str>) {
+        let Some(game) = game_id.and_then(|game_id| self.games.remove(game_id)) else {
+            error!("message handler panicked");
+            return;
+        };
+        error!(game_id = %game.game_id, "message handler panicked, closing game");
+        for member in game.players.iter().chain(&game.spectators) {
+            // Free the seat so the player can join another game
+            if self.player_game.get(member) == Some(&game.game_id) {
+                self.player_game.remove(member);
+            }
+            self.send_to_player(member, ClientMessage::Error("Game closed after an internal error".into()));
+        }
+        self.broadcast_lobby();
+    }
+
+    /// Loads the games and resume tokens saved before the last restart.
+    /// Everyone comes back disconnected, with the usual grace to resume.
+    fn restore(&mut self) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        let (rooms, tokens) = match store.load() {
+            Ok(saved) => saved,
+            Err(e) => {
+                error!(error = %e, "failed to load saved games");
+                return;
+            }
+        };
+
+        let mut seated = HashSet::new();
+        for room in rooms {
+            let mut game = room.into_room();
+            seated.extend(game.players.iter().cloned());
+            seated.extend(game.spectators.iter().cloned());
+            if game.status == GameStatus::InProgress {
+                game.start_turn_clock(&self.tx, self.config.turn_timeout);
+            }
+            if game.status != GameStatus::Finished {
+                for player_id in &game.players {
+                    self.player_game.insert(player_id.clone(), game.game_id.clone());
+                }
+            }
+            self.games.insert(game.game_id.clone(), game);
+        }
+        self.tokens = tokens;
+        info!(games = self.games.len(), "restored saved games");
+        match store.load_players() {
+            Ok(ratings) => self.ratings = ratings,
+            Err(e) => error!(error = %e, "failed to load player ratings"),
+        }
+        let open: Vec<String> = self.games.values()
+            .filter(|game| game.awaits_opponent())
+            .map(|game| game.game_id.clone())
+            .collect();
+        for game_id in open {
+            self.schedule_join_timeout(&game_id);
+        }
+
+        let Some(tx) = self.tx.clone() else {
+            return;
+        };
+        for player_id in seated {
+            self.release_seq += 1;
+            let generation = self.release_seq;
+            self.pending_release.insert(player_id.clone(), generation);
+            let tx = tx.clone();
+            let grace = self.config.reconnect_grace;
+            rt::spawn(async move {
+                time::sleep(grace).await;
+                let _ = tx.send(ServerMessage::ReleaseSession { player_id, generation }).await;
+            });
+        }
+    }
+
+    fn persist(&mut self) {
+        let Some(store) = &mut self.store else {
+            return;
+        };
+        if let Err(e) = store.save(&self.games, &self.tokens) {
+            error!(error = %e, "failed to save games");
+        }
+    }
+
+    /// Updates the ratings of both players once a game is over, when rated
+    /// games are turned on. Games against the bot or with a player who gave
+    /// no name are not rated.
+    fn rate_players(&mut self, game_id: &str) {
+        if !self.config.rated_games {
+            return;
+        }
+        let Some(game) = self.games.get(game_id) else {
+            return;
+        };
+        if game.status != GameStatus::Finished || game.players.contains(BOT_PLAYER_ID) {
+            return;
+        }
+        let (Some(x), Some(o)) = (
+            game.symbols.iter().find(|(_, s)| **s == 'X').map(|(id, _)| id),
+            game.symbols.iter().find(|(_, s)| **s == 'O').map(|(id, _)| id),
+        ) else {
+            return;
+        };
+        if !game.named.contains(x) || !game.named.contains(o) || game.names[x] == game.names[o] {
+            return;
+        }
+        let (x_name, o_name) = (game.names[x].clone(), game.names[o].clone());
+        let x_score = match game.winner {
+            Some('X') => 1.0,
+            Some(_) => 0.0,
+            None => 0.5,
+        };
+
+        let x_before = self.ratings.get(&x_name).cloned().unwrap_or_default();
+        let o_before = self.ratings.get(&o_name).cloned().unwrap_or_default();
+        let x_record = x_before.after_game(o_before.rating, x_score);
+        let o_record = o_before.after_game(x_before.rating, 1.0 - x_score);
+        info!(game_id, x = %x_name, x_rating = x_record.rating, o = %o_name, o_rating = o_record.rating, "ratings updated");
+        for (name, record) in [(x_name, x_record), (o_name, o_record)] {
+            if let Some(store) = &self.store {
+                if let Err(e) = store.save_player(&name, &record) {
+                    error!(player = %name, error = %e, "failed to save rating");
+                }
+            }
+            self.ratings.insert(name, record);
+        }
+    }
+
+    /// Plays a move that has already been checked: the player is seated,
+    /// it is their turn and the cell is free. Ends the game or hands the
+    /// turn over, and tells everyone.
+    fn apply_move(&mut self, game_id: &str, player_id: &str, [row, col]: [usize; 2]) {
+        let Some(game) = self.games.get_mut(game_id) else {
+            return;
+        };
+        let symbol = if game.is_x(player_id) { 'X' } else { 'O' };
+        // Moving on withdraws, or implicitly declines, any pending draw offer
+        game.draw_offer = None;
+        game.board[row][col] = if symbol == 'X' { Cell::X } else { Cell::O };
+        game.moves.push(MoveRecord {
+            player_id: player_id.to_string(),
+            row,
+            col,
+            timestamp: unix_millis(),
+        });
+        game.move_times.push(game.turn_started.map_or(Duration::ZERO, |started| started.elapsed()));
+        self.metrics.moves += 1;
+        // A win on the last empty cell takes precedence over a draw,
+        // which is called as soon as no line can still be completed
+        if let Some((symbol, line)) = game.check_winner() {
+            game.winning_line = line;
+            game.finish(Some(game.variant.winner(symbol)), GameOverReason::Win);
+            self.metrics.record_finish(GameOverReason::Win);
+        } else if game.is_full() || game.is_draw_forced() {
+            game.finish(None, GameOverReason::Draw);
+            self.metrics.record_finish(GameOverReason::Draw);
+        } else {
+            game.x_turn = !game.x_turn;
+            game.start_turn_clock(&self.tx, self.config.turn_timeout);
+        }
+        if let Some(audit) = &self.audit {
+            audit.record(AuditRecord {
+                ts: unix_millis(),
+                game_id: game_id.to_string(),
+                player_id: player_id.to_string(),
+                position: [row, col],
+                resulting_turn: (game.status == GameStatus::InProgress)
+                    .then_some(if game.x_turn { 'X' } else { 'O' }),
+            });
+        }
+        self.rate_players(game_id);
+        self.send_to_player(player_id, ClientMessage::MoveAck { position: [row, col] });
+        self.notify_move(game_id, [row, col], symbol);
+        // Before the match moves on, so a new round does not get two bot moves
+        self.schedule_bot_move(game_id);
+        self.advance_match(game_id);
+    }
+
+    /// Queues the bot's reply if it is the bot's turn. The move goes back
+    /// through the mailbox so it is validated like anyone else's.
+    fn schedule_bot_move(&self, game_id: &str) {
+        let Some(game) = self.games.get(game_id) else {
+            return;
+        };
+        if game.status != GameStatus::InProgress || game.current_player().as_deref() != Some(BOT_PLAYER_ID) {
+            return;
+        }
+        let (Some(tx), Some([row, col])) = (&self.tx, game.bot_move()) else {
+            return;
+        };
+        // Queued from a task: the server must never wait on its own mailbox
+        let tx = tx.clone();
+        let msg = ServerMessage::MakeMove {
+            player_id: BOT_PLAYER_ID.to_string(),
+            game_id: game.game_id.clone(),
+            position: vec![row, col],
+        };
+        rt::spawn(async move {
+            let _ = tx.send(msg).await;
+        });
+    }
+
+    /// Pairs off queued players, closest ratings first, for as long as
+    /// some pair is within the search window of whichever has waited longer.
+    fn matchmake(&mut self) {
+        // Skip anyone who went away, or found a game, since queueing
+        let queue = std::mem::take(&mut self.match_queue);
+        self.match_queue = queue.into_iter()
+            .filter(|queued| self.sessions.contains_key(&queued.player_id) && !self.in_other_game(&queued.player_id, ""))
+            .collect();
+
+        while !self.at_capacity() {
+            // Sorted by rating, so the closest pairs are neighbours
+            let closest = self.match_queue.windows(2)
+                .enumerate()
+                .filter(|(_, pair)| {
+                    let waited = pair[0].since.min(pair[1].since).elapsed();
+                    pair[0].rating.abs_diff(pair[1].rating) <= match_window(waited)
+                })
+                .min_by_key(|(_, pair)| pair[0].rating.abs_diff(pair[1].rating))
+                .map(|(i, _)| i);
+            let Some(i) = closest else {
+                break;
+            };
+            let second = self.match_queue.remove(i + 1);
+            let first = self.match_queue.remove(i);
+            // Whoever waited longer gets the first move
+            let (x, o) = if first.since <= second.since { (first, second) } else { (second, first) };
+
+            let game_id = uuid::Uuid::new_v4().to_string();
+            info!(game_id = %game_id, x = %x.player_id, o = %o.player_id, "quick match made");
+            let mut game = Room::new(game_id.clone(), DEFAULT_BOARD_SIZE, DEFAULT_WIN_LENGTH, 1);
+            for (queued, symbol) in [(x, 'X'), (o, 'O')] {
+                self.player_game.insert(queued.player_id.clone(), game_id.clone());
+                game.symbols.insert(queued.player_id.clone(), symbol);
+                game.names.insert(queued.player_id.clone(), queued.name);
+                if queued.named {
+                    game.named.insert(queued.player_id.clone());
+                }
+                game.players.insert(queued.player_id);
+            }
+            game.begin();
+            game.start_turn_clock(&self.tx, self.config.turn_timeout);
+            let players: Vec<String> = game.players.iter().cloned().collect();
+            self.metrics.games_created += 1;
+            self.games.insert(game_id.clone(), game);
+            for player_id in &players {
+                self.send_joined(&game_id, player_id);
+            }
+            self.notify_game_state(&game_id);
+            self.broadcast_lobby();
+        }
+    }
+
+    /// Kicks seated players who have not acted within the idle timeout.
+    /// Waiting on an opponent who is to move, or for a second player to
+    /// arrive, does not count as idling.
+    fn kick_idle_players(&mut self) {
+        let now = Instant::now();
+        let idle: Vec<String> = self.player_game.iter()
+            .filter(|(player_id, game_id)| {
+                let Some(game) = self.games.get(*game_id) else {
+                    return false;
+                };
+                let waiting_on_opponent = match game.status {
+                    GameStatus::Waiting => true,
+                    GameStatus::InProgress => game.current_player().as_ref() != Some(*player_id),
+                    GameStatus::Finished => false,
+                };
+                game.players.contains(*player_id)
+                    && !waiting_on_opponent
+                    && self.last_action.get(*player_id)
+                        .is_some_and(|at| now - *at > self.config.idle_timeout)
+            })
+            .map(|(player_id, _)| player_id.clone())
+            .collect();
+        for player_id in idle {
+            info!(player_id = %player_id, "kicking idle player");
+            self.send_to_player(&player_id, ClientMessage::Error("kicked for inactivity".into()));
+            self.send_to_player(&player_id, ClientMessage::Close(CloseCause::Idle));
+            // Free the seat now rather than after the reconnect grace
+            self.last_action.remove(&player_id);
+            self.release_player(&player_id);
+        }
+    }
+
+    // Messages carry resume tokens, join codes and chat, so only ids are recorded
+    #[instrument(
+        level = "debug",
+        skip_all,
+        fields(kind = msg.kind(), game_id = msg.game_id(), player_id = msg.sender())
+    )]
+    fn handle(&mut self, msg: ServerMessage) {
+        if let Some(player_id) = msg.acting_player() {
+            if self.sessions.contains_key(player_id) {
+                self.last_action.insert(player_id.to_string(), Instant::now());
+            }
+        }
+        match msg {
+            ServerMessage::Connect { token, sender, reply, move_deltas, peer } => {
+                // A token only resumes a player who is not currently connected
+                let resumed = token.as_ref()
+                    .and_then(|token| self.tokens.get(token))
+                    .filter(|id| !self.sessions.contains_key(*id))
+                    .cloned();
+
+                let (id, token) = match (resumed, token) {
+                    (Some(id), Some(token)) => {
+                        info!(player_id = %id, "player reconnected");
+                        self.pending_release.remove(&id);
+                        (id, token)
+                    }
+                    _ => {
+                        let id = uuid::Uuid::new_v4().to_string();
+                        let token = uuid::Uuid::new_v4().to_string();
+                        info!(player_id = %id, "player connected");
+                        self.tokens.insert(token.clone(), id.clone());
+                        (id, token)
+                    }
+                };
+
+                self.sessions.insert(id.clone(), sender.clone());
+                self.session_info.insert(id.clone(), SessionInfo { peer, connected_at: unix_secs() });
+                self.last_action.insert(id.clone(), Instant::now());
+                if move_deltas {
+                    self.move_deltas.insert(id.clone());
+                } else {
+                    self.move_deltas.remove(&id);
+                }
+                let _ = sender.unbounded_send(ClientMessage::Id { id: id.clone(), token });
+                let _ = reply.send(id.clone());
+
+                // Bring a resumed player back up to date with their games
+                for game in self.games.values() {
+                    if game.players.contains(&id) || game.spectators.contains(&id) {
+                        self.send_state(&id, &Arc::new(game.snapshot()));
+                    }
+                }
+            }
+
+            ServerMessage::Disconnect(id) => {
+                if self.sessions.remove(&id).is_none() {
+                    return;
+                }
+                self.lobby.remove(&id);
+                self.move_deltas.remove(&id);
+                self.session_info.remove(&id);
+                self.last_action.remove(&id);
+                self.match_queue.retain(|queued| queued.player_id != id);
+                info!(player_id = %id, "player disconnected");
+                let members: HashSet<String> = self.games.values()
+                    .filter(|game| game.players.contains(&id) || game.spectators.contains(&id))
+                    .flat_map(|game| game.players.iter().chain(&game.spectators))
+                    .filter(|member| **member != id)
+                    .cloned()
+                    .collect();
+                for member in &members {
+                    self.send_to_player(member, ClientMessage::PlayerLeft { player_id: id.clone() });
+                }
+
+                // A watcher can never come back, so it goes straight away
+                if self.watchers.remove(&id) {
+                    self.release_player(&id);
+                    return;
+                }
+
+                // A seat whose game waits on the player's ready is not held, so
+                // the opponent is not left waiting on someone who has gone
+                if self.games.values().any(|game| game.awaits_ready(&id)) {
+                    self.release_player(&id);
+                    return;
+                }
+
+                // Hold the player's seats for a while in case they reconnect
+                let Some(tx) = self.tx.clone() else {
+                    self.release_player(&id);
+                    return;
+                };
+                self.release_seq += 1;
+                let generation = self.release_seq;
+                self.pending_release.insert(id.clone(), generation);
+                let grace = self.config.reconnect_grace;
+                rt::spawn(async move {
+                    time::sleep(grace).await;
+                    let _ = tx.send(ServerMessage::ReleaseSession { player_id: id, generation }).await;
+                });
+            }
+
+            ServerMessage::Watch { game_id, sender, reply } => {
+                let Some(game) = self.games.get_mut(&game_id).filter(|game| game.code.is_none()) else {
+                    let _ = reply.send(None);
+                    return;
+                };
+                // A session like any other, so it gets what spectators get and
+                // is cleaned up by the usual disconnect path
+                let id = uuid::Uuid::new_v4().to_string();
+                info!(game_id = %game_id, watcher = %id, "watching over server-sent events");
+                let members: Vec<String> = game.players.iter().chain(&game.spectators).cloned().collect();
+                game.spectators.insert(id.clone());
+                let state = Arc::new(game.snapshot());
+                self.sessions.insert(id.clone(), sender);
+                self.watchers.insert(id.clone());
+                for member in &members {
+                    self.send_to_player(member, ClientMessage::PlayerJoined { player_id: id.clone() });
+                }
+                self.send_state(&id, &state);
+                let _ = reply.send(Some(id));
+            }
+
+            ServerMessage::LeaveGame { player_id, game_id } => {
+                let Some(game) = self.games.get_mut(&game_id) else {
+                    self.send_to_player(&player_id, ClientMessage::Error("Game not found".into()));
+                    return;
+                };
+
+                if !game.players.contains(&player_id) && !game.spectators.contains(&player_id) {
+                    self.send_to_player(&player_id, ClientMessage::Error("Not in game".into()));
+                    return;
+                }
+
+                info!(game_id = %game_id, player_id = %player_id, "player left game");
+                // Walking away from a game in play forfeits it, as disconnecting does
+                let forfeit = game.status == GameStatus::InProgress && game.players.contains(&player_id);
+                if forfeit {
+                    let winner = if game.is_x(&player_id) { 'O' } else { 'X' };
+                    info!(game_id = %game_id, player_id = %player_id, "player forfeited by leaving");
+                    game.finish(Some(winner), GameOverReason::OpponentForfeit);
+                    self.metrics.record_finish(GameOverReason::OpponentForfeit);
+                    self.rate_players(&game_id);
+                }
+                let Some(game) = self.games.get_mut(&game_id) else {
+                    return;
+                };
+                let seated = game.players.contains(&player_id);
+                let cancelled = game.remove_member(&player_id);
+                let reopened = seated && game.awaits_opponent();
+                let members: Vec<String> = game.players.iter().chain(&game.spectators).cloned().collect();
+                // Frees the player to join or quick match into another game
+                if self.player_game.get(&player_id) == Some(&game_id) {
+                    self.player_game.remove(&player_id);
+                }
+                for member in &members {
+                    self.send_to_player(member, ClientMessage::PlayerLeft { player_id: player_id.clone() });
+                }
+                for member in cancelled {
+                    self.send_to_player(&member, ClientMessage::Error("Rematch cancelled: opponent left".into()));
+                }
+                self.notify_game_state(&game_id);
+                if forfeit {
+                    self.advance_match(&game_id);
+                }
+                if reopened {
+                    self.schedule_join_timeout(&game_id);
+                }
+                self.broadcast_lobby();
+            }
+
+            ServerMessage::SetFeatures { player_id, move_deltas } => {
+                if !self.sessions.contains_key(&player_id) {
+                    return;
+                }
+                if move_deltas {
+                    self.move_deltas.insert(player_id);
+                } else {
+                    self.move_deltas.remove(&player_id);
+                }
+            }
+
+            ServerMessage::ReleaseSession { player_id, generation } => {
+                // A reconnect, or a later disconnect, supersedes this release
+                if self.pending_release.get(&player_id) != Some(&generation) {
+                    return;
+                }
+                self.pending_release.remove(&player_id);
+                info!(player_id = %player_id, "player released");
+                self.release_player(&player_id);
+            }
+
+            ServerMessage::JoinGame {
+                player_id,
+                game_id,
+                spectate,
+                size,
+                win_length,
+                rounds,
+                variant,
+                timeout_policy,
+                ready_check,
+                name,
+                code,
+            } => {
+                // Seats only go to live connections, so nothing can take the bot's seat id
+                if !self.sessions.contains_key(&player_id) {
+                    warn!(game_id = %game_id, player_id = %player_id, "join from player without a session");
+                    return;
+                }
+                // Joining creates the room, so junk ids would otherwise pile up in the map
+                if !is_valid_id(&game_id) {
+                    self.send_to_player(&player_id, ClientMessage::Error("invalid game id".into()));
+                    return;
+                }
+                let variant = variant.unwrap_or_default();
+                let (size, win_length, rounds) = match validate_rules(variant, size, win_length, rounds) {
+                    Ok(rules) => rules,
+                    Err(e) => {
+                        self.send_to_player(&player_id, ClientMessage::Error(e));
+                        return;
+                    }
+                };
+                // Only players who pick a name get a rating
+                let named = name.as_deref().is_some_and(|name| !name.trim().is_empty());
+                let name = match validate_name(name.as_deref(), &player_id) {
+                    Ok(name) => name,
+                    Err(e) => {
+                        self.send_to_player(&player_id, ClientMessage::Error(e));
+                        return;
+                    }
+                };
+
+                // Watching is unlimited, but each player sits at one running game at a time
+                if !spectate && self.in_other_game(&player_id, &game_id) {
+                    self.send_to_player(&player_id, ClientMessage::Error("already in a game".into()));
+                    return;
+                }
+
+                // While draining, games under way play out but none start
+                if self.draining {
+                    let starts_game = self.games.get(&game_id).is_none_or(|game| {
+                        !spectate && game.status == GameStatus::Waiting && !game.players.contains(&player_id)
+                    });
+                    if starts_game {
+                        self.send_to_player(&player_id, ClientMessage::Error("maintenance".into()));
+                        return;
+                    }
+                }
+
+                // The rules only matter when this join creates the room
+                if !self.games.contains_key(&game_id) {
+                    if self.at_capacity() {
+                        self.send_to_player(&player_id, ClientMessage::Error("server at capacity".into()));
+                        return;
+                    }
+                    self.metrics.games_created += 1;
+                }
+                let game = self.games.entry(game_id.clone())
+                    .or_insert_with(|| {
+                        let mut game = Room::new(game_id.clone(), size, win_length, rounds);
+                        game.variant = variant;
+                        game.timeout_policy = timeout_policy.unwrap_or_default();
+                        game.ready_check = ready_check.unwrap_or(false);
+                        game
+                    });
+
+                // Joining again in the same role just re-sends the current state
+                let seated = game.players.contains(&player_id);
+                let watching = game.spectators.contains(&player_id);
+
+                // Members of a private game are already past the code check
+                if !seated && !watching && game.code.is_some() && game.code != code {
+                    self.send_to_player(&player_id, ClientMessage::Error("wrong code".into()));
+                    return;
+                }
+
+                // Only a rematch brings a finished game back; newcomers are turned away
+                if !seated && !watching && game.status == GameStatus::Finished {
+                    self.send_to_player(&player_id, ClientMessage::Error("Game over".into()));
+                    return;
+                }
+
+                if seated || watching {
+                    if seated == spectate {
+                        let role = if seated { "Already seated" } else { "Already spectating" };
+                        self.send_to_player(&player_id, ClientMessage::Error(format!("{} in this game", role)));
+                    } else {
+                        let state = Arc::new(game.snapshot());
+                        self.send_joined(&game_id, &player_id);
+                        self.send_state(&player_id, &state);
+                    }
+                    return;
+                }
+
+                info!(game_id = %game.game_id, player_id = %player_id, spectate, "player joined");
+                let members: Vec<String> = game.players.iter().chain(&game.spectators).cloned().collect();
+
+                // Seats are only open while the game waits for players;
+                // anyone else, or anyone who asks to watch, becomes a spectator
+                let mut awaits_opponent = false;
+                if spectate || game.status != GameStatus::Waiting || game.players.len() >= 2 {
+                    game.spectators.insert(player_id.clone());
+                } else {
+                    self.player_game.insert(player_id.clone(), game_id.clone());
+                    // First joiner plays X, second plays O
+                    let symbol = if game.symbols.values().any(|s| *s == 'X') { 'O' } else { 'X' };
+                    game.symbols.insert(player_id.clone(), symbol);
+                    game.names.insert(player_id.clone(), name);
+                    if named {
+                        game.named.insert(player_id.clone());
+                    }
+                    game.players.insert(player_id.clone());
+                    awaits_opponent = game.awaits_opponent();
+                    // A game with a ready check starts once both have sent `ready`
+                    if game.players.len() == 2 && !game.ready_check {
+                        game.begin();
+                        game.start_turn_clock(&self.tx, self.config.turn_timeout);
+                    }
+                }
+                for member in &members {
+                    self.send_to_player(member, ClientMessage::PlayerJoined { player_id: player_id.clone() });
+                }
+                self.send_joined(&game_id, &player_id);
+                self.notify_game_state(&game_id);
+                self.broadcast_lobby();
+                if awaits_opponent {
+                    self.schedule_join_timeout(&game_id);
+                }
+            }
+
+            ServerMessage::JoinBot { player_id, size, win_length, name, timeout_policy } => {
+                // The bot only knows the standard rules
+                let (size, win_length, rounds) = match validate_rules(Variant::Standard, size, win_length, None) {
+                    Ok(rules) => rules,
+                    Err(e) => {
+                        self.send_to_player(&player_id, ClientMessage::Error(e));
+                        return;
+                    }
+                };
+                let name = match validate_name(name.as_deref(), &player_id) {
+                    Ok(name) => name,
+                    Err(e) => {
+                        self.send_to_player(&player_id, ClientMessage::Error(e));
+                        return;
+                    }
+                };
+                if self.in_other_game(&player_id, "") {
+                    self.send_to_player(&player_id, ClientMessage::Error("already in a game".into()));
+                    return;
+                }
+                if self.draining {
+                    self.send_to_player(&player_id, ClientMessage::Error("maintenance".into()));
+                    return;
+                }
+                if self.at_capacity() {
+                    self.send_to_player(&player_id, ClientMessage::Error("server at capacity".into()));
+                    return;
+                }
+
+                let game_id = uuid::Uuid::new_v4().to_string();
+                info!(game_id = %game_id, player_id = %player_id, "bot game created");
+                self.player_game.insert(player_id.clone(), game_id.clone());
+                let mut game = Room::new(game_id.clone(), size, win_length, rounds);
+                game.timeout_policy = timeout_policy.unwrap_or_default();
+                game.symbols.insert(player_id.clone(), 'X');
+                game.names.insert(player_id.clone(), name);
+                game.players.insert(player_id.clone());
+                game.symbols.insert(BOT_PLAYER_ID.to_string(), 'O');
+                game.names.insert(BOT_PLAYER_ID.to_string(), "Bot".to_string());
+                game.players.insert(BOT_PLAYER_ID.to_string());
+                game.begin();
+                game.start_turn_clock(&self.tx, self.config.turn_timeout);
+                self.metrics.games_created += 1;
+                self.games.insert(game_id.clone(), game);
+                self.send_joined(&game_id, &player_id);
+                self.notify_game_state(&game_id);
+                self.broadcast_lobby();
+            }
+
+            ServerMessage::QuickMatch { player_id, name } => {
+                if self.match_queue.iter().any(|queued| queued.player_id == player_id) {
+                    self.send_to_player(&player_id, ClientMessage::Error("Already waiting for a match".into()));
+                    return;
+                }
+                if self.in_other_game(&player_id, "") {
+                    self.send_to_player(&player_id, ClientMessage::Error("already in a game".into()));
+                    return;
+                }
+                if self.draining {
+                    self.send_to_player(&player_id, ClientMessage::Error("maintenance".into()));
+                    return;
+                }
+                if self.at_capacity() {
+                    self.send_to_player(&player_id, ClientMessage::Error("server at capacity".into()));
+                    return;
+                }
+                let named = name.as_deref().is_some_and(|name| !name.trim().is_empty());
+                let name = match validate_name(name.as_deref(), &player_id) {
+                    Ok(name) => name,
+                    Err(e) => {
+                        self.send_to_player(&player_id, ClientMessage::Error(e));
+                        return;
+                    }
+                };
+
+                let rating = named
+                    .then(|| self.ratings.get(&name))
+                    .flatten()
+                    .map_or(INITIAL_RATING, |record| record.rating);
+                info!(player_id = %player_id, rating, "waiting for a quick match");
+                let at = self.match_queue.partition_point(|queued| queued.rating <= rating);
+                self.match_queue.insert(at, QueuedPlayer { player_id, name, named, rating, since: Instant::now() });
+                self.matchmake();
+            }
+
+            ServerMessage::Matchmake => self.matchmake(),
+
+            ServerMessage::MakeMove { player_id, game_id, position } => {
+                // The game may have been swept or closed while the move was queued
+                let Some(game) = self.games.get_mut(&game_id) else {
+                    self.send_to_player(&player_id, ClientMessage::Error("game no longer exists".into()));
+                    return;
+                };
+
+                if game.spectators.contains(&player_id) {
+                    self.send_to_player(&player_id, ClientMessage::Error("Spectators cannot move".into()));
+                    return;
+                }
+
+                if !game.players.contains(&player_id) {
+                    self.send_to_player(&player_id, ClientMessage::Error("Not in game".into()));
+                    return;
+                }
+
+                // A seat can outlive its connection; only live sessions, and the bot, may move
+                if player_id != BOT_PLAYER_ID && !self.sessions.contains_key(&player_id) {
+                    warn!(game_id = %game_id, player_id = %player_id, "move from player without a session");
+                    self.send_to_player(&player_id, ClientMessage::Error("Not connected".into()));
+                    return;
+                }
+
+                if let Some(reason) = game.not_in_progress() {
+                    self.send_to_player(&player_id, ClientMessage::Error(reason.into()));
+                    return;
+                }
+
+                let is_x = game.is_x(&player_id);
+                if game.x_turn != is_x {
+                    let msg = ClientMessage::NotYourTurn { current_player: game.current_player(), x_turn: game.x_turn };
+                    self.send_to_player(&player_id, msg);
+                    return;
+                }
+
+                let [row, col] = match game.target_cell(&position) {
+                    Ok(cell) => cell,
+                    Err(code) => {
+                        self.send_to_player(&player_id, ClientMessage::InvalidMove { code, position });
+                        return;
+                    }
+                };
+
+                self.apply_move(&game_id, &player_id, [row, col]);
+            }
+
+            ServerMessage::Rematch { player_id, game_id } => {
+                if self.in_other_game(&player_id, &game_id) {
+                    self.send_to_player(&player_id, ClientMessage::Error("already in a game".into()));
+                    return;
+                }
+                let Some(game) = self.games.get_mut(&game_id) else {
+                    self.send_to_player(&player_id, ClientMessage::Error("Game not found".into()));
+                    return;
+                };
+
+                if !game.players.contains(&player_id) {
+                    self.send_to_player(&player_id, ClientMessage::Error("Not in game".into()));
+                    return;
+                }
+
+                if game.status != GameStatus::Finished {
+                    self.send_to_player(&player_id, ClientMessage::Error("Game not finished".into()));
+                    return;
+                }
+
+                // A rematch is a new game, and none start while draining
+                if self.draining {
+                    self.send_to_player(&player_id, ClientMessage::Error("maintenance".into()));
+                    return;
+                }
+
+                game.rematch_requests.insert(player_id);
+                // The bot is always up for another game
+                if game.players.contains(BOT_PLAYER_ID) {
+                    game.rematch_requests.insert(BOT_PLAYER_ID.to_string());
+                }
+                if game.players.len() == 2 && game.players.is_subset(&game.rematch_requests) {
+                    // A rematch after a decided match starts a fresh one
+                    if game.match_winner().is_some() {
+                        game.score.clear();
+                    }
+                    game.next_round();
+                    if game.status == GameStatus::InProgress {
+                        game.start_turn_clock(&self.tx, self.config.turn_timeout);
+                    }
+                }
+                self.notify_game_state(&game_id);
+                self.schedule_bot_move(&game_id);
+            }
+
+            ServerMessage::Undo { player_id, game_id } => {
+                let Some(game) = self.games.get_mut(&game_id) else {
+                    self.send_to_player(&player_id, ClientMessage::Error("Game not found".into()));
+                    return;
+                };
+
+                if let Some(reason) = game.not_in_progress() {
+                    self.send_to_player(&player_id, ClientMessage::Error(reason.into()));
+                    return;
+                }
+
+                // Only the player who made the last move may take it back
+                if game.moves.last().map(|m| &m.player_id) != Some(&player_id) {
+                    self.send_to_player(&player_id, ClientMessage::Error("Nothing to undo".into()));
+                    return;
+                }
+
+                if let Some(last) = game.moves.pop() {
+                    game.move_times.pop();
+                    game.board[last.row][last.col] = Cell::Empty;
+                    game.x_turn = !game.x_turn;
+                }
+                game.start_turn_clock(&self.tx, self.config.turn_timeout);
+                self.notify_game_state(&game_id);
+            }
+
+            ServerMessage::Chat { player_id, game_id, text } => {
+                let Some(game) = self.games.get_mut(&game_id) else {
+                    self.send_to_player(&player_id, ClientMessage::Error("Game not found".into()));
+                    return;
+                };
+
+                if !game.players.contains(&player_id) && !game.spectators.contains(&player_id) {
+                    self.send_to_player(&player_id, ClientMessage::Error("Not in game".into()));
+                    return;
+                }
+
+                if text.chars().count() > MAX_CHAT_LENGTH {
+                    let message = format!("Chat messages are limited to {} characters", MAX_CHAT_LENGTH);
+                    self.send_to_player(&player_id, ClientMessage::Error(message));
+                    return;
+                }
+
+                game.chat_seq += 1;
+                let seq = game.chat_seq;
+                // Spectators talk among themselves so the players are not distracted
+                let (channel, members): (_, Vec<String>) = if game.players.contains(&player_id) {
+                    (ChatChannel::Players, game.players.iter().chain(&game.spectators).cloned().collect())
+                } else {
+                    (ChatChannel::Spectator, game.spectators.iter().cloned().collect())
+                };
+                for member in members {
+                    self.send_to_player(
+                        &member,
+                        ClientMessage::Chat { from: player_id.clone(), text: text.clone(), seq, channel },
+                    );
+                }
+            }
+
+            ServerMessage::Resign { player_id, game_id } => {
+                let Some(game) = self.games.get_mut(&game_id) else {
+                    self.send_to_player(&player_id, ClientMessage::Error("Game not found".into()));
+                    return;
+                };
+
+                if !game.players.contains(&player_id) {
+                    self.send_to_player(&player_id, ClientMessage::Error("Not in game".into()));
+                    return;
+                }
+
+                if let Some(reason) = game.not_in_progress() {
+                    self.send_to_player(&player_id, ClientMessage::Error(reason.into()));
+                    return;
+                }
+
+                let winner = if game.is_x(&player_id) { 'O' } else { 'X' };
+                info!(game_id = %game_id, player_id = %player_id, "player resigned");
+                game.finish(Some(winner), GameOverReason::Resignation);
+                self.metrics.record_finish(GameOverReason::Resignation);
+                self.rate_players(&game_id);
+                self.notify_game_state(&game_id);
+                self.advance_match(&game_id);
+            }
+
+            ServerMessage::Ready { player_id, game_id } => {
+                let Some(game) = self.games.get_mut(&game_id) else {
+                    self.send_to_player(&player_id, ClientMessage::Error("Game not found".into()));
+                    return;
+                };
+
+                if !game.players.contains(&player_id) {
+                    self.send_to_player(&player_id, ClientMessage::Error("Not in game".into()));
+                    return;
+                }
+
+                if !game.ready_check || game.status != GameStatus::Waiting {
+                    self.send_to_player(&player_id, ClientMessage::Error("Not waiting for ready".into()));
+                    return;
+                }
+
+                game.ready.insert(player_id.clone());
+                let started = game.players.len() == 2 && game.players.iter().all(|id| game.ready.contains(id));
+                if started {
+                    info!(game_id = %game_id, "both players ready");
+                    game.begin();
+                    game.start_turn_clock(&self.tx, self.config.turn_timeout);
+                    let members: Vec<String> = game.players.iter().chain(&game.spectators).cloned().collect();
+                    for member in &members {
+                        self.send_to_player(member, ClientMessage::GameStarted { game_id: game_id.clone() });
+                    }
+                }
+                self.notify_game_state(&game_id);
+                if started {
+                    self.broadcast_lobby();
+                }
+            }
+
+            ServerMessage::DrawOffer { player_id, game_id } => {
+                let Some(game) = self.games.get_mut(&game_id) else {
+                    self.send_to_player(&player_id, ClientMessage::Error("Game not found".into()));
+                    return;
+                };
+
+                if !game.players.contains(&player_id) {
+                    self.send_to_player(&player_id, ClientMessage::Error("Not in game".into()));
+                    return;
+                }
+
+                if let Some(reason) = game.not_in_progress() {
+                    self.send_to_player(&player_id, ClientMessage::Error(reason.into()));
+                    return;
+                }
+
+                if game.draw_offer.is_some() {
+                    self.send_to_player(&player_id, ClientMessage::Error("Draw already offered".into()));
+                    return;
+                }
+
+                game.draw_offer = Some(player_id.clone());
+                let opponents: Vec<String> = game.players.iter().filter(|p| **p != player_id).cloned().collect();
+                for opponent in opponents {
+                    self.send_to_player(&opponent, ClientMessage::DrawOffered { from: player_id.clone() });
+                }
+            }
+
+            ServerMessage::DrawResponse { player_id, game_id, accept } => {
+                let Some(game) = self.games.get_mut(&game_id) else {
+                    self.send_to_player(&player_id, ClientMessage::Error("Game not found".into()));
+                    return;
+                };
+
+                if !game.players.contains(&player_id) {
+                    self.send_to_player(&player_id, ClientMessage::Error("Not in game".into()));
+                    return;
+                }
+
+                // Only the opponent of whoever made the offer can answer it
+                if game.draw_offer.as_ref().is_none_or(|offerer| *offerer == player_id) {
+                    self.send_to_player(&player_id, ClientMessage::Error("No draw offer to answer".into()));
+                    return;
+                }
+
+                if accept {
+                    info!(game_id = %game_id, "draw agreed");
+                    game.finish(None, GameOverReason::Agreement);
+                    self.metrics.record_finish(GameOverReason::Agreement);
+                    self.rate_players(&game_id);
+                } else {
+                    game.draw_offer = None;
+                }
+                self.notify_game_state(&game_id);
+                self.advance_match(&game_id);
+            }
+
+            ServerMessage::TurnTimeout { game_id, expected_turn } => {
+                let Some(game) = self.games.get_mut(&game_id) else {
+                    return;
+                };
+
+                // The turn already ended with a move, undo or game over
+                if game.status != GameStatus::InProgress || game.turn_seq != expected_turn {
+                    return;
+                }
+
+                if game.timeout_policy == TimeoutPolicy::AutoMove {
+                    if let (Some(player_id), Some(cell)) = (game.current_player(), game.random_move()) {
+                        info!(game_id = %game_id, player_id = %player_id, ?cell, "turn timed out, moving for the player");
+                        self.apply_move(&game_id, &player_id, cell);
+                        return;
+                    }
+                }
+
+                let winner = if game.x_turn { 'O' } else { 'X' };
+                game.finish(Some(winner), GameOverReason::Timeout);
+                self.metrics.record_finish(GameOverReason::Timeout);
+                self.rate_players(&game_id);
+                self.notify_game_state(&game_id);
+                self.advance_match(&game_id);
+            }
+
+            ServerMessage::JoinTimeout { game_id, generation } => {
+                // The game may have filled, been replaced or started a new wait in the meantime
+                let still_open = self.games.get(&game_id).is_some_and(|game| {
+                    game.join_generation == generation && game.awaits_opponent()
+                });
+                if !still_open {
+                    return;
+                }
+                let Some(game) = self.games.remove(&game_id) else {
+                    return;
+                };
+                info!(game_id = %game_id, "no opponent joined in time, closing room");
+                for member in game.players.iter().chain(&game.spectators) {
+                    if self.player_game.get(member) == Some(&game_id) {
+                        self.player_game.remove(member);
+                    }
+                    self.send_to_player(member, ClientMessage::Error("no opponent joined in time".into()));
+                }
+                self.broadcast_lobby();
+            }
+
+            ServerMessage::GetGame { game_id, reply } => {
+                let _ = reply.send(self.games.get(&game_id).map(Room::snapshot));
+            }
+
+            ServerMessage::GetMoves { game_id, reply } => {
+                let _ = reply.send(self.games.get(&game_id).map(|game| game.moves.clone()));
+            }
+
+            ServerMessage::GetPlayer { name, reply } => {
+                let _ = reply.send(self.ratings.get(&name).cloned());
+            }
+
+            ServerMessage::Replay { player_id, game_id, delay_ms } => {
+                let Some(game) = self.games.get(&game_id) else {
+                    self.send_to_player(&player_id, ClientMessage::Error("Game not found".into()));
+                    return;
+                };
+                // Private games are only played back to the people in them
+                let member = game.players.contains(&player_id) || game.spectators.contains(&player_id);
+                if game.code.is_some() && !member {
+                    self.send_to_player(&player_id, ClientMessage::Error("Game not found".into()));
+                    return;
+                }
+                let Some(sender) = self.sessions.get(&player_id).cloned() else {
+                    return;
+                };
+                let delay = delay_ms.map_or(DEFAULT_REPLAY_DELAY, Duration::from_millis).min(MAX_REPLAY_DELAY);
+                let frames = game.replay_frames();
+                debug!(game_id = %game_id, player_id = %player_id, frames = frames.len(), "replaying game");
+                rt::spawn(async move {
+                    for frame in frames {
+                        if sender.unbounded_send(ClientMessage::GameState(frame)).is_err() {
+                            break;
+                        }
+                        time::sleep(delay).await;
+                    }
+                });
+            }
+
+            ServerMessage::ForceClose { game_id, reply } => {
+                let Some(game) = self.games.remove(&game_id) else {
+                    let _ = reply.send(false);
+                    return;
+                };
+                warn!(game_id = %game_id, "game closed by admin");
+                for member in game.players.iter().chain(&game.spectators) {
+                    if self.player_game.get(member) == Some(&game_id) {
+                        self.player_game.remove(member);
+                    }
+                    self.send_to_player(member, ClientMessage::Error("game closed by admin".into()));
+                    self.send_to_player(member, ClientMessage::Close(CloseCause::ClosedByAdmin));
+                }
+                let _ = reply.send(true);
+                self.broadcast_lobby();
+            }
+
+            ServerMessage::AdminGame { game_id, reply } => {
+                let view = self.games.get(&game_id).map(|game| {
+                    let players = game.players.iter().map(|id| (id, Role::Player));
+                    let spectators = game.spectators.iter().map(|id| (id, Role::Spectator));
+                    let members = players.chain(spectators)
+                        .filter(|(id, _)| id.as_str() != BOT_PLAYER_ID)
+                        .map(|(id, role)| AdminMember {
+                            player_id: id.clone(),
+                            role,
+                            session: self.session_info.get(id).copied(),
+                        })
+                        .collect();
+                    AdminGameView { game: game.snapshot(), members }
+                });
+                let _ = reply.send(view);
+            }
+
+            ServerMessage::Stats { reply } => {
+                let mut games_by_status = BTreeMap::new();
+                for status in [GameStatus::Waiting, GameStatus::InProgress, GameStatus::Finished] {
+                    games_by_status.insert(status, 0);
+                }
+                for game in self.games.values() {
+                    *games_by_status.entry(game.status).or_default() += 1;
+                }
+                let _ = reply.send(ServerStats {
+                    active_games: self.games.len(),
+                    active_sessions: self.sessions.len(),
+                    games_in_progress: games_by_status[&GameStatus::InProgress],
+                    games_by_status,
+                    queue_depth: self.queue_depth,
+                    metrics: self.metrics.clone(),
+                });
+            }
+
+            ServerMessage::Ping { reply } => {
+                let _ = reply.send(!self.draining);
+            }
+
+            ServerMessage::Announce { text } => {
+                info!(sessions = self.sessions.len(), text = %text, "announcement");
+                for player_id in self.sessions.keys() {
+                    self.send_to_player(player_id, ClientMessage::Announcement { text: text.clone() });
+                }
+            }
+
+            ServerMessage::SetDraining { draining, reply } => {
+                if draining != self.draining {
+                    info!(draining, "maintenance mode changed");
+                }
+                self.draining = draining;
+                if draining {
+                    // Nobody new will be paired, so stop them waiting
+                    for queued in std::mem::take(&mut self.match_queue) {
+                        self.send_to_player(&queued.player_id, ClientMessage::Error("maintenance".into()));
+                    }
+                }
+                let _ = reply.send(());
+            }
+
+            ServerMessage::Shutdown { reply } => {
+                info!(sessions = self.sessions.len(), "notifying sessions of shutdown");
+                for player_id in self.sessions.keys() {
+                    self.send_to_player(player_id, ClientMessage::Error("server shutting down".into()));
+                    self.send_to_player(player_id, ClientMessage::Close(CloseCause::Shutdown));
+                }
+                self.persist();
+                let _ = reply.send(());
+            }
+
+            ServerMessage::Gc => {
+                let now = Instant::now();
+                let grace = self.config.gc_interval;
+                let ttl = self.config.finished_game_ttl;
+                let before = self.games.len();
+                self.games.retain(|_, game| {
+                    // Freshly created rooms get one sweep's grace to be joined
+                    let abandoned = game.players.is_empty()
+                        && game.spectators.is_empty()
+                        && now - game.opened_at > grace;
+                    let expired = game.finished_at.is_some_and(|at| now - at > ttl);
+                    !(abandoned || expired)
+                });
+                let removed = before - self.games.len();
+                if removed > 0 {
+                    info!(removed, remaining = self.games.len(), "swept games");
+                    self.broadcast_lobby();
+                }
+                self.kick_idle_players();
+            }
+
+            ServerMessage::ListGames { status, sort, offset, limit, reply } => {
+                let mut games = self.game_summaries();
+                if let Some(status) = status {
+                    // A waiting room is only worth listing once someone is waiting in it
+                    games.retain(|game| game.status == status && (status != GameStatus::Waiting || game.players == 1));
+                }
+                if sort == GameSort::Players {
+                    // Stable, so the oldest-first order holds among equals
+                    games.sort_by_key(|game| std::cmp::Reverse(game.players));
+                }
+                let total = games.len();
+                let games = games.into_iter().skip(offset).take(limit.unwrap_or(usize::MAX)).collect();
+                let _ = reply.send(GamePage { total, games });
+            }
+
+            ServerMessage::SubscribeLobby { player_id, subscribe } => {
+                if !subscribe {
+                    self.lobby.remove(&player_id);
+                    return;
+                }
+                if !self.sessions.contains_key(&player_id) {
+                    return;
+                }
+                self.lobby.insert(player_id.clone());
+                self.send_to_player(&player_id, ClientMessage::LobbyUpdate(self.game_summaries()));
+            }
+
+            ServerMessage::Persist => self.persist(),
+
+            ServerMessage::CreateGame { size, win_length, rounds, variant, timeout_policy, ready_check, code, reply } => {
+                if self.draining {
+                    let _ = reply.send(Err("maintenance".into()));
+                    return;
+                }
+                if self.at_capacity() {
+                    let _ = reply.send(Err("server at capacity".into()));
+                    return;
+                }
+
+                let game_id = uuid::Uuid::new_v4().to_string();
+                info!(game_id = %game_id, size, win_length, rounds, ?variant, private = code.is_some(), "game created");
+                let mut game = Room::new(game_id.clone(), size, win_length, rounds);
+                game.variant = variant;
+                game.timeout_policy = timeout_policy;
+                game.ready_check = ready_check;
+                game.code = code;
+                self.games.insert(game_id.clone(), game);
+                self.metrics.games_created += 1;
+                let _ = reply.send(Ok(game_id));
+                self.broadcast_lobby();
+            }
+        }
+    }
+}
+
+/// The parts of a room worth keeping across a restart. Clocks, chat
+/// sequence numbers and timestamps start afresh when it is loaded.
+#[derive(Serialize, Deserialize)]
+struct StoredRoom {
+    game_id: String,
+    players: HashSet<String>,
+    symbols: HashMap<String, char>,
+    names: HashMap<String, String>,
+    #[serde(default)]
+    named: HashSet<String>,
+    spectators: HashSet<String>,
+    board: Vec<Vec<Cell>>,
+    x_turn: bool,
+    #[serde(default)]
+    started_by: Option<String>,
+    win_length: usize,
+    rounds: usize,
+    #[serde(default)]
+    variant: Variant,
+    #[serde(default)]
+    timeout_policy: TimeoutPolicy,
+    #[serde(default)]
+    ready_check: bool,
+    #[serde(default)]
+    ready: HashSet<String>,
+    score: HashMap<String, u32>,
+    status: GameStatus,
+    winner: Option<char>,
+    end_reason: Option<GameOverReason>,
+    winning_line: Vec<[usize; 2]>,
+    rematch_requests: HashSet<String>,
+    draw_offer: Option<String>,
+    moves: Vec<MoveRecord>,
+    #[serde(default)]
+    move_times: Vec<Duration>,
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default = "unix_secs")]
+    created_at: u64,
+    #[serde(default)]
+    duration_secs: Option<u64>,
+}
+
+impl StoredRoom {
+    fn from_room(room: &Room) -> Self {
+        StoredRoom {
+            game_id: room.game_id.clone(),
+            players: room.players.clone(),
+            symbols: room.symbols.clone(),
+            names: room.names.clone(),
+            named: room.named.clone(),
+            spectators: room.spectators.clone(),
+            board: room.board.clone(),
+            x_turn: room.x_turn,
+            started_by: room.started_by.clone(),
+            win_length: room.win_length,
+            rounds: room.rounds,
+            variant: room.variant,
+            timeout_policy: room.timeout_policy,
+            ready_check: room.ready_check,
+            ready: room.ready.clone(),
+            score: room.score.clone(),
+            status: room.status,
+            winner: room.winner,
+            end_reason: room.end_reason,
+            winning_line: room.winning_line.clone(),
+            rematch_requests: room.rematch_requests.clone(),
+            draw_offer: room.draw_offer.clone(),
+            moves: room.moves.clone(),
+            move_times: room.move_times.clone(),
+            code: room.code.clone(),
+            created_at: room.created_at,
+            duration_secs: room.duration_secs,
+        }
+    }
+
+    fn into_room(self) -> Room {
+        let size = self.board.len();
+        let mut room = Room::new(self.game_id, size, self.win_length, self.rounds);
+        room.players = self.players;
+        room.symbols = self.symbols;
+        room.names = self.names;
+        room.named = self.named;
+        room.spectators = self.spectators;
+        room.board = self.board;
+        room.x_turn = self.x_turn;
+        room.started_by = self.started_by;
+        room.variant = self.variant;
+        room.timeout_policy = self.timeout_policy;
+        room.ready_check = self.ready_check;
+        room.ready = self.ready;
+        room.score = self.score;
+        room.status = self.status;
+        room.winner = self.winner;
+        room.end_reason = self.end_reason;
+        room.winning_line = self.winning_line;
+        room.rematch_requests = self.rematch_requests;
+        room.draw_offer = self.draw_offer;
+        room.moves = self.moves;
+        room.move_times = self.move_times;
+        // Games saved before think times were kept count their moves as instant
+        room.move_times.resize(room.moves.len(), Duration::ZERO);
+        room.code = self.code;
+        room.created_at = self.created_at;
+        room.duration_secs = self.duration_secs;
+        // Finished games get a full TTL from the restart
+        if room.status == GameStatus::Finished {
+            room.finished_at = Some(Instant::now());
+        }
+        room
+    }
+}
+
+/// SQLite copy of the games map and resume tokens.
+pub struct Store {
+    conn: rusqlite::Connection,
+}
+
+impl Store {
+    fn open(path: &str) -> rusqlite::Result<Self> {
+        let conn = rusqlite::Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS games (game_id TEXT PRIMARY KEY, state TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS tokens (token TEXT PRIMARY KEY, player_id TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS players (
+                 name TEXT PRIMARY KEY,
+                 rating INTEGER NOT NULL,
+                 wins INTEGER NOT NULL,
+                 losses INTEGER NOT NULL,
+                 draws INTEGER NOT NULL
+             );"
+        )?;
+        Ok(Store { conn })
+    }
+
+    fn load(&self) -> rusqlite::Result<(Vec<StoredRoom>, HashMap<String, String>)> {
+        let mut rooms = Vec::new();
+        let mut stmt = self.conn.prepare("SELECT game_id, state FROM games")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+        for row in rows {
+            let (game_id, state) = row?;
+            match serde_json::from_str(&state) {
+                Ok(room) => rooms.push(room),
+                Err(e) => warn!(game_id = %game_id, error = %e, "skipping unreadable saved game"),
+            }
+        }
+
+        let mut stmt = self.conn.prepare("SELECT token, player_id FROM tokens")?;
+        let tokens = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok((rooms, tokens))
+    }
+
+    fn load_players(&self) -> rusqlite::Result<HashMap<String, PlayerRecord>> {
+        let mut stmt = self.conn.prepare("SELECT name, rating, wins, losses, draws FROM players")?;
+        let players = stmt
+            .query_map([], |row| {
+                let record = PlayerRecord {
+                    rating: row.get(1)?,
+                    wins: row.get(2)?,
+                    losses: row.get(3)?,
+                    draws: row.get(4)?,
+                };
+                Ok((row.get(0)?, record))
+            })?
+            .collect();
+        players
+    }
+
+    /// Ratings are written as soon as they change rather than with the
+    /// periodic snapshot, since they outlive the games that produced them.
+    fn save_player(&self, name: &str, record: &PlayerRecord) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO players (name, rating, wins, losses, draws) VALUES (?1, ?2, ?3, ?4, ?5)",
+            (name, record.rating, record.wins, record.losses, record.draws),
+        )?;
+        Ok(())
+    }
+
+    /// Replaces everything saved with the current games and tokens.
+    fn save(&mut self, games: &HashMap<String, Room>, tokens: &HashMap<String, String>) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM games", [])?;
+        tx.execute("DELETE FROM tokens", [])?;
+        {
+            let mut insert = tx.prepare("INSERT INTO games (game_id, state) VALUES (?1, ?2)")?;
+            for (game_id, room) in games {
+                let state = serde_json::to_string(&StoredRoom::from_room(room))
+                    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+                insert.execute((game_id, state))?;
+            }
+            let mut insert = tx.prepare("INSERT INTO tokens (token, player_id) VALUES (?1, ?2)")?;
+            for (token, player_id) in tokens {
+                insert.execute((token, player_id))?;
+            }
+        }
+        tx.commit()
+    }
+}
+
+/// One line of the move audit log.
+#[derive(Debug, Serialize)]
+struct AuditRecord {
+    ts: u64,
+    game_id: String,
+    player_id: String,
+    position: [usize; 2],
+    /// Who is to move next; null once the move ended the game.
+    resulting_turn: Option<char>,
+}
+
+/// Append-only JSON lines log of accepted moves. Records are written by a
+/// thread of its own so file I/O never holds up the game server.
+pub struct AuditLog {
+    tx: std::sync::mpsc::Sender<AuditRecord>,
+}
+
+impl AuditLog {
+    fn open(path: &str) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+        let (tx, rx) = std::sync::mpsc::channel::<AuditRecord>();
+        std::thread::spawn(move || {
+            use std::io::Write;
+            use std::sync::mpsc::TryRecvError;
+
+            let mut out = std::io::BufWriter::new(file);
+            let mut next = rx.recv().ok();
+            while let Some(record) = next {
+                let written = serde_json::to_writer(&mut out, &record)
+                    .map_err(std::io::Error::from)
+                    .and_then(|()| out.write_all(b"\n"));
+                if let Err(e) = written {
+                    error!(error = %e, "failed to write audit log");
+                }
+                // Flush whenever the backlog is cleared, so a quiet server loses nothing
+                next = match rx.try_recv() {
+                    Ok(record) => Some(record),
+                    Err(TryRecvError::Empty) => {
+                        if let Err(e) = out.flush() {
+                            error!(error = %e, "failed to flush audit log");
+                        }
+                        rx.recv().ok()
+                    }
+                    Err(TryRecvError::Disconnected) => None,
+                };
+            }
+            let _ = out.flush();
+        });
+        Ok(AuditLog { tx })
+    }
+
+    fn record(&self, record: AuditRecord) {
+        let _ = self.tx.send(record);
+    }
+}
+
+pub fn start_game_server(config: Config) -> ServerSender {
+    let (tx, mut rx) = tokio::sync::mpsc::channel(config.queue_capacity);
+
+    let store = config.database.as_deref().and_then(|path| match Store::open(path) {
+        Ok(store) => {
+            info!(path, "persisting games to database");
+            Some(store)
+        }
+        Err(e) => {
+            error!(path, error = %e, "failed to open database, keeping games in memory only");
+            None
+        }
+    });
+    if store.is_some() {
+        let persist_tx = tx.clone();
+        let persist_interval = config.persist_interval;
+        rt::spawn(async move {
+            let interval = time::interval(persist_interval);
+            loop {
+                interval.tick().await;
+                if persist_tx.send(ServerMessage::Persist).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    let audit = config.audit_log.as_deref().and_then(|path| match AuditLog::open(path) {
+        Ok(audit) => {
+            info!(path, "writing move audit log");
+            Some(audit)
+        }
+        Err(e) => {
+            error!(path, error = %e, "failed to open audit log, moves will not be audited");
+            None
+        }
+    });
+
+    let match_tx = tx.clone();
+    rt::spawn(async move {
+        let interval = time::interval(MATCHMAKING_INTERVAL);
+        loop {
+            interval.tick().await;
+            if match_tx.send(ServerMessage::Matchmake).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let gc_tx = tx.clone();
+    let gc_interval = config.gc_interval;
+    rt::spawn(async move {
+        let interval = time::interval(gc_interval);
+        loop {
+            interval.tick().await;
+            if gc_tx.send(ServerMessage::Gc).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let server_tx = tx.clone();
+    rt::spawn(async move {
+        let mut server = GameServer {
+            tx: Some(server_tx),
+            config,
+            store,
+            audit,
+            ..Default::default()
+        };
+        server.restore();
+        let capacity = rx.max_capacity();
+        let mut near_full = false;
+        while let Some(msg) = rx.recv().await {
+            // Count each time the backlog climbs past 90% of the mailbox
+            server.queue_depth = rx.len();
+            if !near_full && server.queue_depth * 10 >= capacity * 9 {
+                warn!(depth = server.queue_depth, capacity, "game server queue nearly full");
+                server.metrics.queue_near_full += 1;
+            }
+            near_full = server.queue_depth * 10 >= capacity * 9;
+
+            // A bug in one game must not take the whole server task down with it
+            let game_id = msg.game_id().map(str::to_string);
+            let handled = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| server.handle(msg)));
+            if handled.is_err() {
+                server.quarantine(game_id.as_deref());
+            }
+        }
+    });
+
+    tx
+}
+
+/// Reports a connection's disconnect to the game server exactly once, no
+/// matter how many of the close frame, heartbeat and shutdown paths fire.
+#[derive(Clone)]
+struct DisconnectGuard {
+    server: ServerSender,
+    player_id: String,
+    sent: Arc<AtomicBool>,
+}
+
+impl DisconnectGuard {
+    fn fire(&self) {
+        if !self.sent.swap(true, Ordering::SeqCst) {
+            let msg = ServerMessage::Disconnect(self.player_id.clone());
+            // A full mailbox must not lose the disconnect, so wait for room elsewhere
+            if let Err(TrySendError::Full(msg)) = self.server.try_send(msg) {
+                let server = self.server.clone();
+                rt::spawn(async move {
+                    let _ = server.send(msg).await;
+                });
+            }
+        }
+    }
+}
+
+/// Token bucket refilled continuously at `rate` tokens per second.
+struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: u32) -> Self {
+        RateLimiter {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = (now - self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Open websocket connections per source IP, shared by every worker.
+#[derive(Clone, Default)]
+struct ConnectionCounts(Arc<std::sync::Mutex<HashMap<IpAddr, usize>>>);
+
+impl ConnectionCounts {
+    /// Takes one of the address's connection slots, or `None` if it already has `max` open.
+    fn acquire(&self, ip: IpAddr, max: usize) -> Option<ConnectionSlot> {
+        let mut counts = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let count = counts.entry(ip).or_default();
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionSlot {
+            counts: self.clone(),
+            ip,
+            released: Arc::new(AtomicBool::new(false)),
+        })
+    }
+}
+
+/// A connection's place in its address's count, given back exactly once.
+#[derive(Clone)]
+struct ConnectionSlot {
+    counts: ConnectionCounts,
+    ip: IpAddr,
+    released: Arc<AtomicBool>,
+}
+
+impl ConnectionSlot {
+    fn release(&self) {
+        if self.released.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let mut counts = self.counts.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            // Forget addresses with nothing open so the map does not grow forever
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Longest parse error echoed back; serde quotes parts of the input, which may be huge.
+const MAX_ERROR_DETAIL: usize = 200;
+
+/// Tells the client why its message could not be parsed.
+fn bad_json_frame(e: &serde_json::Error) -> Option<web::ws::Message> {
+    let mut detail = e.to_string();
+    if let Some((end, _)) = detail.char_indices().nth(MAX_ERROR_DETAIL) {
+        detail.truncate(end);
+        detail.push('…');
+    }
+    let error = Outgoing::Error {
+        code: Some(ErrorCode::BadJson),
+        message: ErrorCode::BadJson.message().into(),
+        position: None,
+        detail: Some(detail),
+        current_player: None,
+        x_turn: None,
+    };
+    serde_json::to_string(&error).ok().map(|json| web::ws::Message::Text(json.into()))
+}
+
+fn close_frame(cause: CloseCause) -> web::ws::Message {
+    web::ws::Message::Close(Some(cause.reason()))
+}
+
+#[derive(Clone)]
+struct WsState {
+    tx: Arc<tokio::sync::Mutex<ServerSender>>,
+    hb: Instant,
+    /// What this connection has agreed to receive, replaced by each `hello`.
+    features: Arc<tokio::sync::Mutex<BTreeSet<Feature>>>,
+}
+
+fn server_unavailable() -> web::Error {
+    web::error::ErrorServiceUnavailable::<_, web::DefaultError>("game server unavailable").into()
+}
+
+async fn ws_service(
+    sink: web::ws::WsSink,
+    server: ServerSender,
+    config: Config,
+    token: Option<String>,
+    features: BTreeSet<Feature>,
+    slot: Option<ConnectionSlot>,
+    peer: Option<IpAddr>,
+) -> Result<impl Service<web::ws::Frame, Response = Option<web::ws::Message>, Error = std::io::Error>, web::Error> {
+    let (client_tx, mut client_rx) = mpsc::unbounded();
+
+    // Connect player to server, which tells us who we are
+    let (reply, player_rx) = oneshot::channel();
+    let move_deltas = features.contains(&Feature::Delta);
+    if server.send(ServerMessage::Connect { token, sender: client_tx, reply, move_deltas, peer }).await.is_err() {
+        error!("game server is gone, refusing connection");
+        return Err(server_unavailable());
+    }
+    let Ok(player_id) = player_rx.await else {
+        error!("game server dropped the connect request");
+        return Err(server_unavailable());
+    };
+    let disconnect = DisconnectGuard {
+        server: server.clone(),
+        player_id: player_id.clone(),
+        sent: Arc::new(AtomicBool::new(false)),
+    };
+    let span = info_span!("ws", player_id = %player_id);
+    span.in_scope(|| info!("connection opened"));
+
+    let state = WsState {
+        tx: Arc::new(tokio::sync::Mutex::new(server.clone())),
+        hb: Instant::now(),
+        features: Arc::new(tokio::sync::Mutex::new(features)),
+    };
+
+    // Spawn task to forward messages from server to websocket
+    let sink2 = sink.clone();
+    let forward_features = state.features.clone();
+    let forward_disconnect = disconnect.clone();
+    rt::spawn(async move {
+        while let Some(msg) = client_rx.next().await {
+            if let ClientMessage::Close(cause) = msg {
+                let _ = sink2.send(close_frame(cause)).await;
+                break;
+            }
+            if matches!(msg, ClientMessage::Chat { .. }) && !forward_features.lock().await.contains(&Feature::Chat) {
+                continue;
+            }
+            match serde_json::to_string(&Outgoing::from(msg)) {
+                Ok(json) => {
+                    // The socket is gone, so stop draining messages nobody will read
+                    if let Err(e) = sink2.send(web::ws::Message::Text(json.into())).await {
+                        warn!(error = %e, "failed to send to client, disconnecting");
+                        forward_disconnect.fire();
+                        break;
+                    }
+                }
+                Err(e) => error!(error = %e, "failed to serialize outgoing message"),
+            }
+        }
+    }.instrument(span.clone()));
+
+    // Handler service for incoming websocket frames
+    let tx = state.tx.clone();
+    let hb = Arc::new(tokio::sync::Mutex::new(state.hb));
+    let tx_clone = tx.clone();
+    let hb_clone = hb.clone();
+
+    // Ping the client periodically and drop it once pongs stop arriving
+    let hb_sink = sink.clone();
+    let hb_check = hb.clone();
+    let hb_disconnect = disconnect.clone();
+    rt::spawn(async move {
+        let interval = time::interval(config.heartbeat_interval);
+        loop {
+            interval.tick().await;
+            if Instant::now() - *hb_check.lock().await > config.client_timeout {
+                warn!("heartbeat timed out");
+                hb_disconnect.fire();
+                let _ = hb_sink.send(close_frame(CloseCause::HeartbeatTimeout)).await;
+                break;
+            }
+            if hb_sink.send(web::ws::Message::Ping(Bytes::new())).await.is_err() {
+                break;
+            }
+        }
+    }.instrument(span.clone()));
+
+    let close_disconnect = disconnect.clone();
+    let frame_span = span.clone();
+    let max_frame_size = config.max_frame_size;
+    let limiter = Arc::new(tokio::sync::Mutex::new(RateLimiter::new(config.move_rate)));
+    // Fragments of a message still being received
+    let partial: Arc<tokio::sync::Mutex<Option<Vec<u8>>>> = Arc::default();
+    let features = state.features.clone();
+    let frame_player_id = player_id.clone();
+    let service = fn_service(move |frame| {
+        let tx = tx_clone.clone();
+        let features = features.clone();
+        let player_id = frame_player_id.clone();
+        let limiter = limiter.clone();
+        let hb = hb_clone.clone();
+        let close_disconnect = close_disconnect.clone();
+        let partial = partial.clone();
+        async move {
+            // Text, binary and reassembled messages all carry the same JSON
+            let payload = match frame {
+                web::ws::Frame::Ping(msg) => return Ok(Some(web::ws::Message::Pong(msg))),
+                web::ws::Frame::Pong(_) => {
+                    *hb.lock().await = Instant::now();
+                    return Ok(None);
+                }
+                web::ws::Frame::Text(payload) | web::ws::Frame::Binary(payload) => payload.to_vec(),
+                web::ws::Frame::Continuation(item) => {
+                    let mut partial = partial.lock().await;
+                    let (chunk, last) = match item {
+                        ntex::ws::Item::FirstText(chunk) | ntex::ws::Item::FirstBinary(chunk) => {
+                            *partial = Some(Vec::new());
+                            (chunk, false)
+                        }
+                        ntex::ws::Item::Continue(chunk) => (chunk, false),
+                        ntex::ws::Item::Last(chunk) => (chunk, true),
+                    };
+                    let Some(buf) = partial.as_mut() else {
+                        return Ok(None);
+                    };
+                    buf.extend_from_slice(&chunk);
+                    // Stop buffering as soon as the message is over the limit
+                    if !last && buf.len() <= max_frame_size {
+                        return Ok(None);
+                    }
+                    partial.take().unwrap_or_default()
+                }
+                web::ws::Frame::Close(reason) => {
+                    close_disconnect.fire();
+                    return Ok(Some(web::ws::Message::Close(reason)));
+                }
+            };
+            // No legitimate message comes close; anything bigger is abuse.
+            // The limit applies to a reassembled message as a whole.
+            if payload.len() > max_frame_size {
+                warn!(size = payload.len(), "frame too large, closing connection");
+                close_disconnect.fire();
+                return Ok(Some(close_frame(CloseCause::FrameTooLarge)));
+            }
+            let Ok(text) = std::str::from_utf8(&payload) else {
+                warn!("message is not valid UTF-8, closing connection");
+                close_disconnect.fire();
+                return Ok(Some(close_frame(CloseCause::InvalidPayload)));
+            };
+            let msg = match serde_json::from_str::<Incoming>(text) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    debug!(error = %e, "rejected malformed message");
+                    return Ok(bad_json_frame(&e));
+                }
+            };
+            // Ids are public, so a connection may only ever act as its own player
+            if msg.player().is_some_and(|claimed| claimed != player_id) {
+                warn!(claimed = ?msg.player(), "message sent as another player, closing connection");
+                close_disconnect.fire();
+                return Ok(Some(close_frame(CloseCause::PolicyViolation)));
+            }
+            if matches!(msg, Incoming::Move { .. }) && !limiter.lock().await.try_acquire() {
+                warn!("move rate limit exceeded, closing connection");
+                close_disconnect.fire();
+                return Ok(Some(close_frame(CloseCause::PolicyViolation)));
+            }
+            if let Incoming::Hello { features: requested } = &msg {
+                let negotiated: BTreeSet<Feature> = requested.iter().filter_map(|name| Feature::parse(name)).collect();
+                debug!(features = ?negotiated, "features negotiated");
+                *features.lock().await = negotiated.clone();
+                let command = ServerMessage::SetFeatures {
+                    player_id,
+                    move_deltas: negotiated.contains(&Feature::Delta),
+                };
+                if tx.lock().await.send(command).await.is_err() {
+                    error!("game server is gone, closing connection");
+                    return Ok(Some(close_frame(CloseCause::Internal)));
+                }
+                let hello = Outgoing::Hello {
+                    server_version: SERVER_VERSION,
+                    protocol_version: PROTOCOL_VERSION,
+                    features: negotiated,
+                };
+                return Ok(serde_json::to_string(&hello).ok().map(|json| web::ws::Message::Text(json.into())));
+            }
+            let Some(command) = msg.into_server_message(player_id) else {
+                return Ok(None);
+            };
+            // Waits while the server is backed up, which slows down this client's reads
+            if tx.lock().await.send(command).await.is_err() {
+                error!("game server is gone, closing connection");
+                return Ok(Some(close_frame(CloseCause::Internal)));
+            }
+            Ok(None)
+        }.instrument(frame_span.clone())
+    });
+
+    let on_shutdown = fn_shutdown(move || {
+        span.in_scope(|| info!("connection closed"));
+        disconnect.fire();
+        if let Some(slot) = &slot {
+            slot.release();
+        }
+    });
+
+    Ok(chain(service).and_then(on_shutdown))
+}
+
+
+#[derive(Deserialize)]
+struct ConnectParams {
+    token: Option<String>,
+    /// Comma-separated optional protocol features, e.g. `move_delta`.
+    capabilities: Option<String>,
+}
+
+async fn ws_index(
+    req: web::HttpRequest,
+    server: web::types::State<ServerSender>,
+    config: web::types::State<Config>,
+    connections: web::types::State<ConnectionCounts>,
+    params: web::types::Query<ConnectParams>,
+) -> Result<web::HttpResponse, web::Error> {
+    // Browsers always send an Origin, so this stops other sites from opening sockets
+    if !config.allowed_origins.is_empty() {
+        let origin = req.headers().get("origin").and_then(|origin| origin.to_str().ok());
+        if !origin.is_some_and(|origin| config.allowed_origins.iter().any(|allowed| allowed == origin)) {
+            warn!(?origin, "rejected websocket from disallowed origin");
+            return Ok(web::HttpResponse::Forbidden().finish());
+        }
+    }
+
+    // One host must not be able to use up the server's sockets
+    let slot = match req.peer_addr() {
+        Some(peer) => match connections.acquire(peer.ip(), config.max_connections_per_ip) {
+            Some(slot) => Some(slot),
+            None => {
+                warn!(ip = %peer.ip(), "rejected websocket over the per-IP connection limit");
+                return Ok(web::HttpResponse::TooManyRequests().finish());
+            }
+        },
+        None => None,
+    };
+
+    let ConnectParams { token, capabilities } = params.into_inner();
+    // Chat is on unless a later `hello` leaves it out
+    let mut features = BTreeSet::from([Feature::Chat]);
+    features.extend(capabilities.as_deref().unwrap_or_default().split(',').filter_map(Feature::parse));
+    let service_slot = slot.clone();
+    let peer = req.peer_addr().map(|peer| peer.ip());
+    let response = web::ws::start(
+        req,
+        fn_factory_with_config(move |sink| {
+            ws_service(
+                sink,
+                server.get_ref().clone(),
+                config.get_ref().clone(),
+                token.clone(),
+                features.clone(),
+                service_slot.clone(),
+                peer,
+            )
+        }),
+    ).await;
+    // A failed handshake or refused connect never reaches the shutdown hook
+    if let (Err(_), Some(slot)) = (&response, &slot) {
+        slot.release();
+    }
+    response
+}
+
+/// Sends a request to the game server and waits for its reply.
+/// Returns `None` if the server task is gone.
+async fn ask<T>(
+    server: &ServerSender,
+    msg: impl FnOnce(oneshot::Sender<T>) -> ServerMessage,
+) -> Option<T> {
+    let (reply, rx) = oneshot::channel();
+    server.send(msg(reply)).await.ok()?;
+    rx.await.ok()
+}
+
+#[derive(Deserialize)]
+struct CreateGameParams {
+    size: Option<usize>,
+    win_length: Option<usize>,
+    rounds: Option<usize>,
+    variant: Option<Variant>,
+    timeout_policy: Option<TimeoutPolicy>,
+    #[serde(default)]
+    ready_check: bool,
+    code: Option<String>,
+}
+
+async fn create_game(
+    server: web::types::State<ServerSender>,
+    params: web::types::Query<CreateGameParams>,
+) -> web::HttpResponse {
+    let variant = params.variant.unwrap_or_default();
+    let (size, win_length, rounds) = match validate_rules(variant, params.size, params.win_length, params.rounds) {
+        Ok(rules) => rules,
+        Err(e) => return web::HttpResponse::BadRequest().json(&serde_json::json!({ "error": e })),
+    };
+    let code = match params.code.as_deref().map(validate_code).transpose() {
+        Ok(code) => code,
+        Err(e) => return web::HttpResponse::BadRequest().json(&serde_json::json!({ "error": e })),
+    };
+
+    let timeout_policy = params.timeout_policy.unwrap_or_default();
+    let ready_check = params.ready_check;
+    let msg = |reply| ServerMessage::CreateGame { size, win_length, rounds, variant, timeout_policy, ready_check, code, reply };
+    match ask(server.get_ref(), msg).await {
+        Some(Ok(game_id)) => web::HttpResponse::Ok().json(&serde_json::json!({ "game_id": game_id })),
+        Some(Err(e)) => web::HttpResponse::ServiceUnavailable().json(&serde_json::json!({ "error": e })),
+        None => web::HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+/// Checks an admin request carries `Authorization: Bearer <XO_ADMIN_TOKEN>`,
+/// returning the response to send if it does not. Admin routes do not
+/// exist at all unless a token is configured.
+fn reject_non_admin(req: &web::HttpRequest, config: &Config) -> Option<web::HttpResponse> {
+    let Some(admin_token) = &config.admin_token else {
+        return Some(web::HttpResponse::NotFound().finish());
+    };
+    let presented = req.headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if presented != Some(admin_token.as_str()) {
+        warn!("rejected admin request with a missing or wrong token");
+        return Some(web::HttpResponse::Unauthorized().finish());
+    }
+    None
+}
+
+/// Removes a game for moderation. Admin only.
+async fn delete_game(
+    req: web::HttpRequest,
+    server: web::types::State<ServerSender>,
+    config: web::types::State<Config>,
+    game_id: web::types::Path<String>,
+) -> web::HttpResponse {
+    if let Some(rejection) = reject_non_admin(&req, &config) {
+        return rejection;
+    }
+
+    let game_id = game_id.into_inner();
+    match ask(server.get_ref(), |reply| ServerMessage::ForceClose { game_id, reply }).await {
+        Some(true) => web::HttpResponse::NoContent().finish(),
+        Some(false) => web::HttpResponse::NotFound().finish(),
+        None => web::HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+/// Turns maintenance mode on (`POST`) or off (`DELETE`). Admin only.
+async fn drain(
+    req: web::HttpRequest,
+    server: web::types::State<ServerSender>,
+    config: web::types::State<Config>,
+) -> web::HttpResponse {
+    if let Some(rejection) = reject_non_admin(&req, &config) {
+        return rejection;
+    }
+
+    let draining = req.method() == ntex::http::Method::POST;
+    match ask(server.get_ref(), |reply| ServerMessage::SetDraining { draining, reply }).await {
+        Some(()) => web::HttpResponse::NoContent().finish(),
+        None => web::HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+/// A game with each member's address and connect time. Admin only.
+async fn admin_game(
+    req: web::HttpRequest,
+    server: web::types::State<ServerSender>,
+    config: web::types::State<Config>,
+    game_id: web::types::Path<String>,
+) -> web::HttpResponse {
+    if let Some(rejection) = reject_non_admin(&req, &config) {
+        return rejection;
+    }
+
+    let game_id = game_id.into_inner();
+    match ask(server.get_ref(), |reply| ServerMessage::AdminGame { game_id, reply }).await {
+        Some(Some(view)) => web::HttpResponse::Ok().json(&view),
+        Some(None) => web::HttpResponse::NotFound().finish(),
+        None => web::HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+#[derive(Deserialize)]
+struct AnnounceBody {
+    text: String,
+}
+
+/// Sends `{"text": ..}` to every connected session. Admin only.
+async fn announce(
+    req: web::HttpRequest,
+    server: web::types::State<ServerSender>,
+    config: web::types::State<Config>,
+    body: web::types::Json<AnnounceBody>,
+) -> web::HttpResponse {
+    if let Some(rejection) = reject_non_admin(&req, &config) {
+        return rejection;
+    }
+
+    let text = body.into_inner().text.trim().to_string();
+    if text.is_empty() || text.chars().count() > MAX_ANNOUNCEMENT_LENGTH {
+        let error = format!("announcements must be 1 to {} characters", MAX_ANNOUNCEMENT_LENGTH);
+        return web::HttpResponse::BadRequest().json(&serde_json::json!({ "error": error }));
+    }
+    match server.send(ServerMessage::Announce { text }).await {
+        Ok(()) => web::HttpResponse::NoContent().finish(),
+        Err(_) => web::HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+#[derive(Deserialize)]
+struct ListGamesParams {
+    status: Option<GameStatus>,
+    #[serde(default)]
+    sort: GameSort,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+async fn list_games(
+    server: web::types::State<ServerSender>,
+    params: web::types::Query<ListGamesParams>,
+) -> web::HttpResponse {
+    let ListGamesParams { status, sort, offset, limit } = params.into_inner();
+    match ask(server.get_ref(), |reply| ServerMessage::ListGames { status, sort, offset, limit, reply }).await {
+        Some(games) => web::HttpResponse::Ok().json(&games),
+        None => web::HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+/// A game as its players see it, with think times once it is over.
+async fn game(
+    server: web::types::State<ServerSender>,
+    game_id: web::types::Path<String>,
+) -> web::HttpResponse {
+    let game_id = game_id.into_inner();
+    match ask(server.get_ref(), |reply| ServerMessage::GetGame { game_id, reply }).await {
+        Some(Some(game)) => web::HttpResponse::Ok().json(&game),
+        Some(None) => web::HttpResponse::NotFound().finish(),
+        None => web::HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+async fn game_moves(
+    server: web::types::State<ServerSender>,
+    game_id: web::types::Path<String>,
+) -> web::HttpResponse {
+    let game_id = game_id.into_inner();
+    match ask(server.get_ref(), |reply| ServerMessage::GetMoves { game_id, reply }).await {
+        Some(Some(moves)) => web::HttpResponse::Ok().json(&moves),
+        Some(None) => web::HttpResponse::NotFound().finish(),
+        None => web::HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+/// What a server-sent events watcher receives: the same JSON a websocket
+/// spectator gets, one `data:` line per message. Dropping it, as ntex does
+/// once the client goes away, disconnects the watcher and frees its
+/// address's connection slot.
+struct EventStream {
+    rx: mpsc::UnboundedReceiver<ClientMessage>,
+    disconnect: DisconnectGuard,
+    slot: Option<ConnectionSlot>,
+}
+
+impl futures::Stream for EventStream {
+    type Item = Result<Bytes, std::convert::Infallible>;
+
+    fn poll_next(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Option<Self::Item>> {
+        loop {
+            let msg = match self.rx.poll_next_unpin(cx) {
+                std::task::Poll::Ready(Some(ClientMessage::Close(_))) | std::task::Poll::Ready(None) => {
+                    return std::task::Poll::Ready(None);
+                }
+                std::task::Poll::Ready(Some(msg)) => msg,
+                std::task::Poll::Pending => return std::task::Poll::Pending,
+            };
+            match serde_json::to_string(&Outgoing::from(msg)) {
+                Ok(json) => return std::task::Poll::Ready(Some(Ok(Bytes::from(format!("data: {}\n\n", json))))),
+                Err(e) => error!(error = %e, "failed to serialize outgoing message"),
+            }
+        }
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        self.disconnect.fire();
+        if let Some(slot) = &self.slot {
+            slot.release();
+        }
+    }
+}
+
+/// Streams a public game's updates as `text/event-stream`, for watchers
+/// that cannot open a websocket.
+async fn game_events(
+    req: web::HttpRequest,
+    server: web::types::State<ServerSender>,
+    config: web::types::State<Config>,
+    connections: web::types::State<ConnectionCounts>,
+    game_id: web::types::Path<String>,
+) -> web::HttpResponse {
+    // Watchers hold a connection open just like websockets, so they share the limit
+    let slot = match req.peer_addr() {
+        Some(peer) => match connections.acquire(peer.ip(), config.max_connections_per_ip) {
+            Some(slot) => Some(slot),
+            None => {
+                warn!(ip = %peer.ip(), "rejected event stream over the per-IP connection limit");
+                return web::HttpResponse::TooManyRequests().finish();
+            }
+        },
+        None => None,
+    };
+
+    let (sender, rx) = mpsc::unbounded();
+    let game_id = game_id.into_inner();
+    let response = match ask(server.get_ref(), |reply| ServerMessage::Watch { game_id, sender, reply }).await {
+        Some(Some(watcher)) => {
+            let disconnect = DisconnectGuard {
+                server: server.get_ref().clone(),
+                player_id: watcher,
+                sent: Arc::new(AtomicBool::new(false)),
+            };
+            return web::HttpResponse::Ok()
+                .content_type("text/event-stream")
+                .header("cache-control", "no-cache")
+                .streaming(EventStream { rx, disconnect, slot });
+        }
+        Some(None) => web::HttpResponse::NotFound().finish(),
+        None => web::HttpResponse::ServiceUnavailable().finish(),
+    };
+    if let Some(slot) = slot {
+        slot.release();
+    }
+    response
+}
+
+/// A named player's rating and win/loss/draw record.
+async fn player(
+    server: web::types::State<ServerSender>,
+    name: web::types::Path<String>,
+) -> web::HttpResponse {
+    let name = name.into_inner();
+    match ask(server.get_ref(), |reply| ServerMessage::GetPlayer { name: name.clone(), reply }).await {
+        Some(Some(record)) => web::HttpResponse::Ok().json(&serde_json::json!({
+            "name": name,
+            "rating": record.rating,
+            "wins": record.wins,
+            "losses": record.losses,
+            "draws": record.draws,
+        })),
+        Some(None) => web::HttpResponse::NotFound().finish(),
+        None => web::HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+/// Renders server stats in the Prometheus text exposition format.
+fn render_metrics(stats: &ServerStats) -> String {
+    use std::fmt::Write;
+
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{}{} {}", name, labels, value);
+        }
+    };
+
+    metric("xo_active_games", "gauge", "Games currently held by the server.",
+        &[(String::new(), stats.active_games as u64)]);
+    metric("xo_active_sessions", "gauge", "Connected websocket sessions.",
+        &[(String::new(), stats.active_sessions as u64)]);
+    metric("xo_games_created_total", "counter", "Games created since startup.",
+        &[(String::new(), stats.metrics.games_created)]);
+    let finished: Vec<_> = GameOverReason::ALL
+        .iter()
+        .map(|reason| {
+            let count = stats.metrics.games_finished.get(reason.as_str()).copied().unwrap_or(0);
+            (format!("{{result=\"{}\"}}", reason.as_str()), count)
+        })
+        .collect();
+    metric("xo_games_finished_total", "counter", "Games finished since startup, by result.", &finished);
+    metric("xo_moves_total", "counter", "Moves accepted since startup.",
+        &[(String::new(), stats.metrics.moves)]);
+    metric("xo_server_queue_depth", "gauge", "Commands waiting for the game server.",
+        &[(String::new(), stats.queue_depth as u64)]);
+    metric("xo_server_queue_near_full_total", "counter", "Times the game server queue filled past 90%.",
+        &[(String::new(), stats.metrics.queue_near_full)]);
+    out
+}
+
+async fn metrics(
+    server: web::types::State<ServerSender>,
+) -> web::HttpResponse {
+    match ask(server.get_ref(), |reply| ServerMessage::Stats { reply }).await {
+        Some(stats) => web::HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(render_metrics(&stats)),
+        None => web::HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+async fn stats(
+    server: web::types::State<ServerSender>,
+) -> web::HttpResponse {
+    match ask(server.get_ref(), |reply| ServerMessage::Stats { reply }).await {
+        Some(stats) => web::HttpResponse::Ok().json(&stats),
+        None => web::HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+/// Liveness: the HTTP server is up. Deliberately does not touch the game server.
+async fn health() -> web::HttpResponse {
+    web::HttpResponse::Ok().finish()
+}
+
+/// Readiness: the game server task is alive and draining its mailbox.
+async fn ready(
+    server: web::types::State<ServerSender>,
+) -> web::HttpResponse {
+    // Draining counts as not ready, so load balancers send new players elsewhere
+    match ask(server.get_ref(), |reply| ServerMessage::Ping { reply }).await {
+        Some(true) => web::HttpResponse::Ok().finish(),
+        Some(false) | None => web::HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+/// Builds the TLS settings from the configured certificate and key, if
+/// both are set. Setting only one of them, or unreadable files, stop
+/// startup rather than quietly falling back to plain HTTP.
+fn tls_config(config: &Config) -> std::io::Result<Option<rustls::ServerConfig>> {
+    use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+
+    let (cert, key) = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) => return Ok(None),
+        _ => {
+            return Err(std::io::Error::other(
+                "XO_TLS_CERT and XO_TLS_KEY must be set together",
+            ));
+        }
+    };
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| std::io::Error::other(format!("reading {}: {}", cert, e)))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| std::io::Error::other(format!("reading {}: {}", key, e)))?;
+    rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map(Some)
+        .map_err(std::io::Error::other)
+}
+
+#[ntex::main]
+async fn main() -> std::io::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"))
+        )
+        .init();
+
+    let config = Config::from_env();
+    let tls = tls_config(&config)?;
+    let server = start_game_server(config.clone());
+    let connections = ConnectionCounts::default();
+    let bind = config.bind.clone();
+    // The same fallback ntex uses, so the logged count is the real one
+    let workers = config.workers.unwrap_or_else(|| {
+        std::thread::available_parallelism().map_or(2, |n| n.get())
+    });
+    info!(address = %bind, workers, tls = tls.is_some(), "listening");
+
+    // ntex stops the listener on SIGTERM/SIGINT but lets open connections
+    // drain, so tell every client why before their sockets go away
+    let shutdown_server = server.clone();
+    rt::spawn(async move {
+        loop {
+            match ntex::server::signal().await {
+                Ok(ntex::server::Signal::Hup) => continue,
+                Ok(signal) => {
+                    info!(?signal, "shutting down");
+                    break;
+                }
+                Err(_) => return,
+            }
+        }
+        let _ = ask(&shutdown_server, |reply| ServerMessage::Shutdown { reply }).await;
+    });
+
+    let http = web::HttpServer::new(move || {
+        web::App::new()
+            .state(server.clone())
+            .state(config.clone())
+            .state(connections.clone())
+            .wrap(web::middleware::Logger::default())
+            .service(web::resource("/ws/").route(web::get().to(ws_index)))
+            .service(
+                web::resource("/games")
+                    .route(web::get().to(list_games))
+                    .route(web::post().to(create_game))
+            )
+            .service(
+                web::resource("/games/{id}")
+                    .route(web::get().to(game))
+                    .route(web::delete().to(delete_game))
+            )
+            .service(web::resource("/games/{id}/moves").route(web::get().to(game_moves)))
+            .service(web::resource("/games/{id}/events").route(web::get().to(game_events)))
+            .service(
+                web::resource("/admin/drain")
+                    .route(web::post().to(drain))
+                    .route(web::delete().to(drain))
+            )
+            .service(web::resource("/admin/games/{id}").route(web::get().to(admin_game)))
+            .service(web::resource("/announce").route(web::post().to(announce)))
+            .service(web::resource("/players/{name}").route(web::get().to(player)))
+            .service(web::resource("/metrics").route(web::get().to(metrics)))
+            .service(web::resource("/stats").route(web::get().to(stats)))
+            .service(web::resource("/health").route(web::get().to(health)))
+            .service(web::resource("/ready").route(web::get().to(ready)))
+    })
+        .workers(workers);
+    let http = match tls {
+        Some(tls) => http.bind_rustls(bind, tls)?,
+        None => http.bind(bind)?,
+    };
+    http.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc::UnboundedReceiver;
+    use proptest::prelude::*;
+
+    /// A connected player, with the messages the server sent it.
+    struct TestClient {
+        id: String,
+        rx: UnboundedReceiver<ClientMessage>,
+    }
+
+    impl TestClient {
+        fn connect(server: &mut GameServer) -> TestClient {
+            let (sender, rx) = mpsc::unbounded();
+            let (reply, mut id_rx) = oneshot::channel();
+            let peer = Some(IpAddr::from([127, 0, 0, 1]));
+            server.handle(ServerMessage::Connect { token: None, sender, reply, move_deltas: false, peer });
+            let id = id_rx.try_recv().unwrap().expect("connect was not answered");
+            TestClient { id, rx }
+        }
+
+        /// Everything sent to this client since the last call.
+        fn drain(&mut self) -> Vec<ClientMessage> {
+            std::iter::from_fn(|| self.rx.try_next().ok().flatten()).collect()
+        }
+
+        fn errors(&mut self) -> Vec<String> {
+            self.drain()
+                .into_iter()
+                .filter_map(|msg| match msg {
+                    ClientMessage::Error(e) => Some(e),
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    /// The parts of a join the tests vary; everything else is left unset.
+    #[derive(Default)]
+    struct JoinOpts {
+        spectate: bool,
+        variant: Option<Variant>,
+        ready_check: Option<bool>,
+        name: Option<&'static str>,
+    }
+
+    fn join(server: &mut GameServer, client: &TestClient, game_id: &str) {
+        join_with(server, client, game_id, JoinOpts::default());
+    }
+
+    fn join_with(server: &mut GameServer, client: &TestClient, game_id: &str, opts: JoinOpts) {
+        server.handle(ServerMessage::JoinGame {
+            player_id: client.id.clone(),
+            game_id: game_id.into(),
+            spectate: opts.spectate,
+            size: None,
+            win_length: None,
+            rounds: None,
+            variant: opts.variant,
+            timeout_policy: None,
+            ready_check: opts.ready_check,
+            name: opts.name.map(str::to_string),
+            code: None,
+        });
+    }
+
+    fn play(server: &mut GameServer, client: &TestClient, game_id: &str, row: usize, col: usize) {
+        server.handle(ServerMessage::MakeMove {
+            player_id: client.id.clone(),
+            game_id: game_id.into(),
+            position: vec![row, col],
+        });
+    }
+
+    /// A server with two players seated in game `g`, X first.
+    fn seated() -> (GameServer, TestClient, TestClient) {
+        let mut server = GameServer::default();
+        let mut x = TestClient::connect(&mut server);
+        let mut o = TestClient::connect(&mut server);
+        join(&mut server, &x, "g");
+        join(&mut server, &o, "g");
+        x.drain();
+        o.drain();
+        (server, x, o)
+    }
+
+    #[test]
+    fn joining_creates_a_room() {
+        let mut server = GameServer::default();
+        let mut player = TestClient::connect(&mut server);
+        join(&mut server, &player, "g");
+
+        let game = &server.games["g"];
+        assert!(game.players.contains(&player.id));
+        assert_eq!(game.symbols[&player.id], 'X');
+        assert_eq!(game.status, GameStatus::Waiting);
+        assert!(player.drain().iter().any(|msg| matches!(msg, ClientMessage::GameState(state) if state.game_id == "g")));
+    }
+
+    #[test]
+    fn new_rooms_are_refused_at_capacity() {
+        let mut server = GameServer::default();
+        server.config.max_games = 1;
+        let first = TestClient::connect(&mut server);
+        let mut second = TestClient::connect(&mut server);
+        join(&mut server, &first, "g");
+        join(&mut server, &second, "h");
+
+        assert!(!server.games.contains_key("h"));
+        assert_eq!(second.errors(), ["server at capacity"]);
+        let (reply, mut rx) = oneshot::channel();
+        server.handle(ServerMessage::CreateGame {
+            size: 3,
+            win_length: 3,
+            rounds: 1,
+            variant: Variant::Standard,
+            timeout_policy: TimeoutPolicy::Forfeit,
+            ready_check: false,
+            code: None,
+            reply,
+        });
+        assert_eq!(rx.try_recv().unwrap(), Some(Err("server at capacity".into())));
+
+        // Existing rooms can still be joined
+        join(&mut server, &second, "g");
+        assert_eq!(server.games["g"].players.len(), 2);
+    }
+
+    #[test]
+    fn nobody_can_join_as_the_bot() {
+        let mut server = GameServer::default();
+        let host = TestClient::connect(&mut server);
+        join(&mut server, &host, "g");
+        // The bot's seat id, claimed by something with no connection behind it
+        let bot = TestClient { id: BOT_PLAYER_ID.into(), rx: mpsc::unbounded().1 };
+        join(&mut server, &bot, "g");
+
+        assert!(!server.games["g"].players.contains(BOT_PLAYER_ID));
+        assert_eq!(server.games["g"].status, GameStatus::Waiting);
+    }
+
+    #[test]
+    fn a_player_sits_in_one_game_but_may_watch_others() {
+        let (mut server, mut x, _o) = seated();
+        let host = TestClient::connect(&mut server);
+        join(&mut server, &host, "b");
+        join(&mut server, &x, "b");
+
+        assert!(!server.games["b"].players.contains(&x.id));
+        assert_eq!(x.errors(), ["already in a game"]);
+
+        join_with(&mut server, &x, "b", JoinOpts { spectate: true, ..Default::default() });
+        assert!(server.games["b"].spectators.contains(&x.id));
+        assert!(x.errors().is_empty());
+    }
+
+    #[test]
+    fn joining_twice_keeps_one_seat() {
+        let mut server = GameServer::default();
+        let mut player = TestClient::connect(&mut server);
+        join(&mut server, &player, "g");
+        join(&mut server, &player, "g");
+
+        let game = &server.games["g"];
+        assert_eq!(game.players.len(), 1);
+        assert_eq!(game.status, GameStatus::Waiting);
+        // The second join just re-sends the state
+        let states = player.drain().into_iter().filter(|msg| matches!(msg, ClientMessage::GameState(_))).count();
+        assert_eq!(states, 2);
+    }
+
+    #[test]
+    fn joiners_are_told_their_role_and_symbol() {
+        let mut server = GameServer::default();
+        let mut x = TestClient::connect(&mut server);
+        let mut o = TestClient::connect(&mut server);
+        let mut watcher = TestClient::connect(&mut server);
+        join(&mut server, &x, "g");
+        join(&mut server, &o, "g");
+        join_with(&mut server, &watcher, "g", JoinOpts { spectate: true, ..Default::default() });
+
+        let joined = |client: &mut TestClient| client.drain().into_iter().find_map(|msg| match msg {
+            ClientMessage::Joined { role, symbol, .. } => Some((role, symbol)),
+            _ => None,
+        });
+        assert_eq!(joined(&mut x), Some((Role::Player, Some('X'))));
+        assert_eq!(joined(&mut o), Some((Role::Player, Some('O'))));
+        assert_eq!(joined(&mut watcher), Some((Role::Spectator, None)));
+    }
+
+    #[test]
+    fn open_room_closes_when_nobody_joins_in_time() {
+        let (mut server, _x, _o) = seated();
+        let mut waiting = TestClient::connect(&mut server);
+        join(&mut server, &waiting, "open");
+        waiting.drain();
+        for game_id in ["g", "open"] {
+            let generation = server.games[game_id].join_generation;
+            server.handle(ServerMessage::JoinTimeout { game_id: game_id.into(), generation });
+        }
+
+        // A room that filled in the meantime is left alone
+        assert_eq!(server.games["g"].status, GameStatus::InProgress);
+        assert!(!server.games.contains_key("open"));
+        assert!(!server.player_game.contains_key(&waiting.id));
+        assert_eq!(waiting.errors(), ["no opponent joined in time"]);
+    }
+
+    #[test]
+    fn room_waits_again_once_the_opponent_goes_before_the_start() {
+        let (mut server, mut x, o) = seated_awaiting_ready();
+        let first = server.games["g"].join_generation;
+        server.handle(ServerMessage::LeaveGame { player_id: o.id.clone(), game_id: "g".into() });
+        let second = server.games["g"].join_generation;
+        assert_ne!(first, second);
+
+        // The wait from the first join is over, the new one still runs
+        server.handle(ServerMessage::JoinTimeout { game_id: "g".into(), generation: first });
+        assert!(server.games.contains_key("g"));
+        x.drain();
+        server.handle(ServerMessage::JoinTimeout { game_id: "g".into(), generation: second });
+        assert!(!server.games.contains_key("g"));
+        assert_eq!(x.errors(), ["no opponent joined in time"]);
+
+        // An unready disconnect reopens the seat the same way
+        let (mut server, _x, o) = seated_awaiting_ready();
+        let first = server.games["g"].join_generation;
+        server.handle(ServerMessage::Disconnect(o.id.clone()));
+        assert_ne!(server.games["g"].join_generation, first);
+    }
+
+    #[test]
+    fn second_player_starts_the_game() {
+        let (server, x, o) = seated();
+        let game = &server.games["g"];
+        assert_eq!(game.status, GameStatus::InProgress);
+        assert_eq!(game.symbols[&o.id], 'O');
+        assert_eq!(game.current_player(), Some(x.id.clone()));
+    }
+
+    #[test]
+    fn third_player_is_refused_a_seat() {
+        let (mut server, _x, _o) = seated();
+        let third = TestClient::connect(&mut server);
+        join(&mut server, &third, "g");
+
+        let game = &server.games["g"];
+        assert_eq!(game.players.len(), 2);
+        assert!(!game.players.contains(&third.id));
+        assert!(game.spectators.contains(&third.id));
+    }
+
+    #[test]
+    fn valid_move_updates_board_and_flips_turn() {
+        let (mut server, mut x, _o) = seated();
+        play(&mut server, &x, "g", 1, 2);
+
+        let game = &server.games["g"];
+        assert_eq!(game.board[1][2], Cell::X);
+        assert!(!game.x_turn);
+        let sent = x.drain();
+        assert!(!sent.iter().any(|msg| matches!(msg, ClientMessage::Error(_))));
+        assert!(matches!(sent.first(), Some(ClientMessage::MoveAck { position: [1, 2] })));
+    }
+
+    #[test]
+    fn out_of_turn_move_is_rejected() {
+        let (mut server, x, mut o) = seated();
+        play(&mut server, &o, "g", 0, 0);
+
+        let game = &server.games["g"];
+        assert_eq!(game.board[0][0], Cell::Empty);
+        assert!(game.x_turn);
+        let told = o.drain().into_iter().any(|msg| {
+            matches!(msg, ClientMessage::NotYourTurn { current_player: Some(id), x_turn: true } if id == x.id)
+        });
+        assert!(told);
+    }
+
+    #[test]
+    fn occupied_cell_is_rejected() {
+        let (mut server, x, mut o) = seated();
+        play(&mut server, &x, "g", 0, 0);
+        o.drain();
+        play(&mut server, &o, "g", 0, 0);
+
+        assert!(!server.games["g"].x_turn);
+        let rejected = o.drain().into_iter().any(|msg| {
+            matches!(msg, ClientMessage::InvalidMove { code: ErrorCode::CellOccupied, .. })
+        });
+        assert!(rejected);
+    }
+
+    #[test]
+    fn malformed_positions_are_rejected_with_their_shape() {
+        let (mut server, mut x, _o) = seated();
+        for (position, expected) in [
+            (vec![], ErrorCode::EmptyPosition),
+            (vec![1], ErrorCode::PositionTooShort),
+            (vec![1, 1, 1], ErrorCode::PositionTooLong),
+        ] {
+            server.handle(ServerMessage::MakeMove {
+                player_id: x.id.clone(),
+                game_id: "g".into(),
+                position: position.clone(),
+            });
+            let rejected = x.drain().into_iter().any(|msg| {
+                matches!(msg, ClientMessage::InvalidMove { code, position: sent } if code == expected && sent == position)
+            });
+            assert!(rejected, "{position:?} should be rejected with {expected:?}");
+        }
+        assert!(server.games["g"].moves.is_empty());
+    }
+
+    #[test]
+    fn rematch_hands_the_first_move_to_the_other_player() {
+        let (mut server, x, o) = finished();
+        assert_eq!(server.games["g"].started_by.as_ref(), Some(&x.id));
+        for player in [&x, &o] {
+            server.handle(ServerMessage::Rematch { player_id: player.id.clone(), game_id: "g".into() });
+        }
+
+        let game = &server.games["g"];
+        assert_eq!(game.status, GameStatus::InProgress);
+        assert_eq!(game.symbols[&o.id], 'X');
+        assert_eq!(game.symbols[&x.id], 'O');
+        assert_eq!(game.started_by.as_ref(), Some(&o.id));
+        assert_eq!(game.current_player(), Some(o.id.clone()));
+    }
+
+    #[test]
+    fn members_hear_about_joins_and_departures() {
+        let mut server = GameServer::default();
+        let mut x = TestClient::connect(&mut server);
+        let o = TestClient::connect(&mut server);
+        join(&mut server, &x, "g");
+        x.drain();
+        join(&mut server, &o, "g");
+        let joined = x.drain().into_iter().any(|msg| {
+            matches!(msg, ClientMessage::PlayerJoined { player_id } if player_id == o.id)
+        });
+        assert!(joined);
+
+        server.handle(ServerMessage::Disconnect(o.id.clone()));
+        let left = x.drain().into_iter().any(|msg| {
+            matches!(msg, ClientMessage::PlayerLeft { player_id } if player_id == o.id)
+        });
+        assert!(left);
+    }
+
+    #[test]
+    fn leaving_frees_the_player_for_another_game() {
+        let (mut server, mut x, mut o) = seated();
+        server.handle(ServerMessage::LeaveGame { player_id: x.id.clone(), game_id: "g".into() });
+
+        let game = &server.games["g"];
+        assert!(!game.players.contains(&x.id));
+        assert_eq!(game.status, GameStatus::Finished);
+        assert!(server.sessions.contains_key(&x.id));
+        let left = o.drain().into_iter().any(|msg| {
+            matches!(msg, ClientMessage::PlayerLeft { player_id } if player_id == x.id)
+        });
+        assert!(left);
+
+        join(&mut server, &x, "h");
+        assert!(server.games["h"].players.contains(&x.id));
+        assert!(x.errors().is_empty());
+    }
+
+    #[test]
+    fn listing_waiting_games_shows_only_open_seats() {
+        let (mut server, _x, _o) = seated();
+        let waiting = TestClient::connect(&mut server);
+        join(&mut server, &waiting, "open");
+        let (reply, _) = oneshot::channel();
+        server.handle(ServerMessage::CreateGame {
+            size: 3,
+            win_length: 3,
+            rounds: 1,
+            variant: Variant::Standard,
+            timeout_policy: TimeoutPolicy::Forfeit,
+            ready_check: false,
+            code: None,
+            reply,
+        });
+
+        let list = |server: &mut GameServer, status| {
+            let (reply, mut page) = oneshot::channel();
+            server.handle(ServerMessage::ListGames { status, sort: GameSort::CreatedAt, offset: 0, limit: None, reply });
+            page.try_recv().unwrap().unwrap().games
+        };
+        assert_eq!(list(&mut server, None).len(), 3);
+        let open = list(&mut server, Some(GameStatus::Waiting));
+        assert_eq!(open.len(), 1);
+        assert_eq!(open[0].game_id, "open");
+        assert_eq!(list(&mut server, Some(GameStatus::InProgress))[0].game_id, "g");
+    }
+
+    #[test]
+    fn games_list_pages_after_sorting() {
+        let mut server = GameServer::default();
+        let (x, o, waiting) = (
+            TestClient::connect(&mut server),
+            TestClient::connect(&mut server),
+            TestClient::connect(&mut server),
+        );
+        for (player, game_id) in [(&waiting, "a"), (&x, "b"), (&o, "b")] {
+            join(&mut server, player, game_id);
+        }
+        server.games.get_mut("a").unwrap().created_at = 1;
+        server.games.get_mut("b").unwrap().created_at = 2;
+
+        let page = |server: &mut GameServer, sort, offset, limit| {
+            let (reply, mut page) = oneshot::channel();
+            server.handle(ServerMessage::ListGames { status: None, sort, offset, limit, reply });
+            let page = page.try_recv().unwrap().unwrap();
+            (page.total, page.games.into_iter().map(|game| game.game_id).collect::<Vec<_>>())
+        };
+        assert_eq!(page(&mut server, GameSort::CreatedAt, 0, None), (2, vec!["a".to_string(), "b".to_string()]));
+        assert_eq!(page(&mut server, GameSort::Players, 0, Some(1)), (2, vec!["b".to_string()]));
+        assert_eq!(page(&mut server, GameSort::Players, 1, Some(5)), (2, vec!["a".to_string()]));
+        assert_eq!(page(&mut server, GameSort::CreatedAt, 2, None), (2, vec![]));
+    }
+
+    #[test]
+    fn spectator_chat_stays_among_spectators() {
+        let (mut server, mut x, _o) = seated();
+        let mut watcher = TestClient::connect(&mut server);
+        join_with(&mut server, &watcher, "g", JoinOpts { spectate: true, ..Default::default() });
+        x.drain();
+        watcher.drain();
+
+        let chat = |server: &mut GameServer, from: &TestClient| {
+            server.handle(ServerMessage::Chat { player_id: from.id.clone(), game_id: "g".into(), text: "hi".into() });
+        };
+        let channels = |client: &mut TestClient| -> Vec<ChatChannel> {
+            client.drain().into_iter().filter_map(|msg| match msg {
+                ClientMessage::Chat { channel, .. } => Some(channel),
+                _ => None,
+            }).collect()
+        };
+        chat(&mut server, &watcher);
+        assert!(channels(&mut x).is_empty());
+        assert_eq!(channels(&mut watcher), [ChatChannel::Spectator]);
+
+        chat(&mut server, &x);
+        assert_eq!(channels(&mut x), [ChatChannel::Players]);
+        assert_eq!(channels(&mut watcher), [ChatChannel::Players]);
+    }
+
+    #[test]
+    fn named_players_are_rated_when_the_game_ends() {
+        let mut server = GameServer::default();
+        server.config.rated_games = true;
+        let x = TestClient::connect(&mut server);
+        let o = TestClient::connect(&mut server);
+        for (player, name) in [(&x, "alice"), (&o, "bob")] {
+            join_with(&mut server, player, "g", JoinOpts { name: Some(name), ..Default::default() });
+        }
+        server.handle(ServerMessage::Resign { player_id: o.id.clone(), game_id: "g".into() });
+
+        let alice = &server.ratings["alice"];
+        let bob = &server.ratings["bob"];
+        assert_eq!((alice.rating, alice.wins), (INITIAL_RATING + 16, 1));
+        assert_eq!((bob.rating, bob.losses), (INITIAL_RATING - 16, 1));
+
+        // Unnamed players are left out
+        let (mut server, x, _o) = seated();
+        server.config.rated_games = true;
+        server.handle(ServerMessage::Resign { player_id: x.id.clone(), game_id: "g".into() });
+        assert!(server.ratings.is_empty());
+    }
+
+    #[test]
+    fn games_are_unrated_by_default() {
+        let mut server = GameServer::default();
+        for name in ["alice", "bob"] {
+            let player = TestClient::connect(&mut server);
+            join_with(&mut server, &player, "g", JoinOpts { name: Some(name), ..Default::default() });
+        }
+        let x = server.games["g"].symbols.iter().find(|(_, s)| **s == 'X').unwrap().0.clone();
+        server.handle(ServerMessage::Resign { player_id: x, game_id: "g".into() });
+        assert_eq!(server.games["g"].status, GameStatus::Finished);
+        assert!(server.ratings.is_empty());
+    }
+
+    #[test]
+    fn quick_match_pairs_close_ratings_first_and_anyone_eventually() {
+        let mut server = GameServer::default();
+        server.ratings.insert("strong".into(), PlayerRecord { rating: 1800, ..Default::default() });
+        let clients: Vec<TestClient> = (0..3).map(|_| TestClient::connect(&mut server)).collect();
+        for (client, name) in clients.iter().zip(["strong", "new", "newer"]) {
+            server.handle(ServerMessage::QuickMatch { player_id: client.id.clone(), name: Some(name.into()) });
+        }
+
+        // The two new players are paired; the strong one keeps waiting
+        assert_eq!(server.games.len(), 1);
+        let game = server.games.values().next().unwrap();
+        assert!(game.players.contains(&clients[1].id) && game.players.contains(&clients[2].id));
+        assert_eq!(server.match_queue.len(), 1);
+
+        let late = TestClient::connect(&mut server);
+        server.handle(ServerMessage::QuickMatch { player_id: late.id.clone(), name: None });
+        assert_eq!(server.games.len(), 1);
+        for queued in &mut server.match_queue {
+            queued.since -= MATCH_ANYONE_AFTER;
+        }
+        server.handle(ServerMessage::Matchmake);
+        assert_eq!(server.games.len(), 2);
+        assert!(server.match_queue.is_empty());
+    }
+
+    #[test]
+    fn force_close_removes_the_game_and_closes_members() {
+        let (mut server, mut x, _o) = seated();
+        let (reply, mut existed) = oneshot::channel();
+        server.handle(ServerMessage::ForceClose { game_id: "g".into(), reply });
+
+        assert_eq!(existed.try_recv(), Ok(Some(true)));
+        assert!(!server.games.contains_key("g"));
+        let closed = x.drain().into_iter().any(|msg| matches!(msg, ClientMessage::Close(CloseCause::ClosedByAdmin)));
+        assert!(closed);
+    }
+
+    #[test]
+    fn connections_per_ip_are_capped_until_released() {
+        let counts = ConnectionCounts::default();
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let first = counts.acquire(ip, 2).unwrap();
+        let _second = counts.acquire(ip, 2).unwrap();
+        assert!(counts.acquire(ip, 2).is_none());
+        assert!(counts.acquire(IpAddr::from([10, 0, 0, 1]), 2).is_some());
+
+        // Releasing twice only frees one slot
+        first.release();
+        first.release();
+        assert!(counts.acquire(ip, 2).is_some());
+        assert!(counts.acquire(ip, 2).is_none());
+    }
+
+    /// `seated`, with X having won along the top row.
+    fn finished() -> (GameServer, TestClient, TestClient) {
+        let (mut server, mut x, mut o) = seated();
+        for (player, row, col) in [(&x, 0, 0), (&o, 1, 0), (&x, 0, 1), (&o, 1, 1), (&x, 0, 2)] {
+            play(&mut server, player, "g", row, col);
+        }
+        assert_eq!(server.games["g"].status, GameStatus::Finished);
+        x.drain();
+        o.drain();
+        (server, x, o)
+    }
+
+    #[test]
+    fn finished_game_refuses_new_joiners() {
+        let (mut server, _x, _o) = finished();
+        let mut late = TestClient::connect(&mut server);
+        join(&mut server, &late, "g");
+
+        let game = &server.games["g"];
+        assert!(!game.players.contains(&late.id));
+        assert!(!game.spectators.contains(&late.id));
+        assert_eq!(late.errors(), ["Game over"]);
+    }
+
+    #[test]
+    fn finished_game_refuses_moves() {
+        let (mut server, _x, mut o) = finished();
+        let board = server.games["g"].board.clone();
+        play(&mut server, &o, "g", 2, 2);
+
+        assert_eq!(server.games["g"].board, board);
+        assert_eq!(o.errors(), ["Game over"]);
+    }
+
+    #[test]
+    fn draining_refuses_new_games_but_lets_running_ones_finish() {
+        let (mut server, mut x, o) = seated();
+        let mut waiting = TestClient::connect(&mut server);
+        join(&mut server, &waiting, "open");
+        waiting.drain();
+        let (reply, _rx) = oneshot::channel();
+        server.handle(ServerMessage::SetDraining { draining: true, reply });
+
+        let mut late = TestClient::connect(&mut server);
+        join(&mut server, &late, "new");
+        join(&mut server, &late, "open");
+        server.handle(ServerMessage::QuickMatch { player_id: late.id.clone(), name: None });
+        assert_eq!(late.errors(), ["maintenance"; 3]);
+        assert!(!server.games.contains_key("new"));
+        assert_eq!(server.games["open"].players.len(), 1);
+
+        for (player, row, col) in [(&x, 0, 0), (&o, 1, 0), (&x, 0, 1), (&o, 1, 1), (&x, 0, 2)] {
+            play(&mut server, player, "g", row, col);
+        }
+        assert_eq!(server.games["g"].status, GameStatus::Finished);
+
+        // Nor does a rematch once it is over
+        server.handle(ServerMessage::Rematch { player_id: x.id.clone(), game_id: "g".into() });
+        assert_eq!(x.errors(), ["maintenance"]);
+        assert_eq!(server.games["g"].status, GameStatus::Finished);
+
+        let (reply, _rx) = oneshot::channel();
+        server.handle(ServerMessage::SetDraining { draining: false, reply });
+        join(&mut server, &late, "open");
+        assert_eq!(server.games["open"].status, GameStatus::InProgress);
+    }
+
+    #[test]
+    fn admin_view_shows_where_members_connect_from() {
+        let (mut server, x, o) = seated();
+        let (reply, mut rx) = oneshot::channel();
+        server.handle(ServerMessage::AdminGame { game_id: "g".into(), reply });
+
+        let view = rx.try_recv().unwrap().expect("not answered").expect("game exists");
+        assert_eq!(view.members.len(), 2);
+        for id in [&x.id, &o.id] {
+            let member = view.members.iter().find(|member| member.player_id == *id).expect("member listed");
+            assert_eq!(member.role, Role::Player);
+            let session = member.session.expect("member is connected");
+            assert_eq!(session.peer, Some(IpAddr::from([127, 0, 0, 1])));
+            assert!(session.connected_at > 0);
+        }
+
+        // The public view has none of it
+        let public = serde_json::to_string(&server.games["g"].snapshot()).unwrap();
+        assert!(!public.contains("127.0.0.1"));
+    }
+
+    #[test]
+    fn half_a_tls_config_refuses_to_start() {
+        let config = Config { tls_cert: Some("cert.pem".into()), ..Config::default() };
+        assert!(tls_config(&config).is_err());
+        let config = Config { tls_key: Some("key.pem".into()), ..Config::default() };
+        assert!(tls_config(&config).is_err());
+        assert!(tls_config(&Config::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn announcements_reach_every_session() {
+        let (mut server, mut x, mut o) = seated();
+        let mut idle = TestClient::connect(&mut server);
+        server.handle(ServerMessage::Announce { text: "restarting soon".into() });
+
+        for client in [&mut x, &mut o, &mut idle] {
+            let heard = client.drain().into_iter().any(|msg| {
+                matches!(msg, ClientMessage::Announcement { text } if text == "restarting soon")
+            });
+            assert!(heard);
+        }
+    }
+
+    #[test]
+    fn finished_game_reports_think_times() {
+        let (mut server, mut x, o) = seated();
+        assert!(server.games["g"].snapshot().think_times.is_none());
+        for (player, row, col) in [(&x, 0, 0), (&o, 1, 0), (&x, 0, 1), (&o, 1, 1), (&x, 0, 2)] {
+            play(&mut server, player, "g", row, col);
+        }
+
+        let think_times = server.games["g"].snapshot().think_times.expect("game is over");
+        assert_eq!(think_times[&x.id].moves, 3);
+        assert_eq!(think_times[&o.id].moves, 2);
+        assert!(think_times[&x.id].avg_ms <= think_times[&x.id].max_ms);
+        assert!(x.drain().iter().any(|msg| matches!(
+            msg,
+            ClientMessage::GameOver { think_times, .. } if think_times.len() == 2
+        )));
+    }
+
+    #[test]
+    fn timed_out_turn_is_played_for_the_player_under_auto_move() {
+        let (mut server, _x, _o) = seated();
+        server.games.get_mut("g").unwrap().timeout_policy = TimeoutPolicy::AutoMove;
+        let expected_turn = server.games["g"].turn_seq;
+        server.handle(ServerMessage::TurnTimeout { game_id: "g".into(), expected_turn });
+
+        let game = &server.games["g"];
+        assert_eq!(game.status, GameStatus::InProgress);
+        assert_eq!(game.moves.len(), 1);
+        assert!(!game.x_turn);
+        assert_eq!(game.empty_cells().len(), 8);
+    }
+
+    #[test]
+    fn idle_player_to_move_is_kicked() {
+        let (mut server, mut x, mut o) = seated();
+        server.config.idle_timeout = Duration::ZERO;
+        std::thread::sleep(Duration::from_millis(5));
+        server.handle(ServerMessage::Gc);
+
+        let game = &server.games["g"];
+        assert!(!game.players.contains(&x.id));
+        assert!(game.players.contains(&o.id));
+        assert!(x.drain().into_iter().any(|msg| matches!(msg, ClientMessage::Close(CloseCause::Idle))));
+        assert!(o.errors().is_empty());
+    }
+
+    #[test]
+    fn chatting_does_not_keep_an_idle_player_seated() {
+        let (mut server, x, _o) = seated();
+        server.config.idle_timeout = Duration::from_millis(20);
+        std::thread::sleep(Duration::from_millis(30));
+        server.handle(ServerMessage::Chat { player_id: x.id.clone(), game_id: "g".into(), text: "still here".into() });
+        server.handle(ServerMessage::Gc);
+
+        assert!(!server.games["g"].players.contains(&x.id));
+    }
+
+    #[test]
+    fn names_outside_the_allowed_characters_are_refused() {
+        let mut server = GameServer::default();
+        let mut player = TestClient::connect(&mut server);
+        for name in ["<script>", "a/b", "semi;colon"] {
+            join_with(&mut server, &player, "g", JoinOpts { name: Some(name), ..Default::default() });
+        }
+        assert!(server.games.is_empty());
+        assert_eq!(player.errors().len(), 3);
+
+        assert_eq!(validate_name(Some(" Jo Ann-Lee_2 "), "p"), Ok("Jo Ann-Lee_2".into()));
+    }
+
+    #[test]
+    fn invalid_game_id_creates_no_room() {
+        let mut server = GameServer::default();
+        let mut player = TestClient::connect(&mut server);
+        for game_id in ["", "has space", "slash/id", &"x".repeat(MAX_ID_LENGTH + 1)] {
+            join(&mut server, &player, game_id);
+        }
+
+        assert!(server.games.is_empty());
+        assert_eq!(player.errors().len(), 4);
+        assert!(is_valid_id(&uuid::Uuid::new_v4().to_string()));
+    }
+
+    #[test]
+    fn move_position_parses_as_array_or_object() {
+        for json in [
+            r#"{"type":"move","player":"p","game_id":"g","position":[1,2]}"#,
+            r#"{"type":"move","player":"p","game_id":"g","move":{"row":1,"col":2}}"#,
+        ] {
+            let msg: Incoming = serde_json::from_str(json).unwrap();
+            let Some(ServerMessage::MakeMove { position, .. }) = msg.into_server_message("p".into()) else {
+                panic!("{} is not a move", json);
+            };
+            assert_eq!(position, [1, 2]);
+        }
+    }
+
+    #[test]
+    fn completing_a_line_loses_in_misere() {
+        let mut server = GameServer::default();
+        let x = TestClient::connect(&mut server);
+        let o = TestClient::connect(&mut server);
+        for player in [&x, &o] {
+            join_with(&mut server, player, "g", JoinOpts { variant: Some(Variant::Misere), ..Default::default() });
+        }
+        for (player, row, col) in [(&x, 0, 0), (&o, 1, 0), (&x, 0, 1), (&o, 1, 1), (&x, 0, 2)] {
+            play(&mut server, player, "g", row, col);
+        }
+
+        let game = &server.games["g"];
+        assert_eq!(game.status, GameStatus::Finished);
+        assert_eq!(game.winner, Some('O'));
+        assert_eq!(game.score.get(&o.id), Some(&1));
+    }
+
+    #[test]
+    fn connect_four_pieces_fall_to_the_bottom() {
+        let mut server = GameServer::default();
+        let mut x = TestClient::connect(&mut server);
+        let o = TestClient::connect(&mut server);
+        for player in [&x, &o] {
+            join_with(&mut server, player, "g", JoinOpts { variant: Some(Variant::ConnectFour), ..Default::default() });
+        }
+        let drop_in = |server: &mut GameServer, player: &TestClient, col: usize| {
+            server.handle(ServerMessage::MakeMove {
+                player_id: player.id.clone(),
+                game_id: "g".into(),
+                position: vec![col],
+            });
+        };
+
+        drop_in(&mut server, &x, 3);
+        drop_in(&mut server, &o, 3);
+        let game = &server.games["g"];
+        assert_eq!((game.board.len(), game.win_length), (7, 4));
+        assert_eq!(game.board[6][3], Cell::X);
+        assert_eq!(game.board[5][3], Cell::O);
+
+        // A full position is too long for Connect Four
+        x.drain();
+        play(&mut server, &x, "g", 0, 0);
+        assert!(server.games["g"].x_turn);
+        assert!(x.drain().iter().any(|msg| {
+            matches!(msg, ClientMessage::InvalidMove { code: ErrorCode::PositionTooLong, .. })
+        }));
+        for col in [0, 1, 0, 1, 0, 1, 0] {
+            let player = if server.games["g"].x_turn { &x } else { &o };
+            drop_in(&mut server, player, col);
+        }
+        let game = &server.games["g"];
+        assert_eq!(game.status, GameStatus::Finished);
+        assert_eq!(game.winner, Some('X'));
+        assert_eq!(game.winning_line, [[3, 0], [4, 0], [5, 0], [6, 0]]);
+    }
+
+    #[test]
+    fn replay_rebuilds_each_position_from_the_moves() {
+        let (server, _x, _o) = finished();
+        let game = &server.games["g"];
+        let frames = game.replay_frames();
+
+        assert_eq!(frames.len(), game.moves.len() + 1);
+        for (placed, frame) in frames.iter().enumerate() {
+            assert_eq!(count(&frame.board, Cell::X) + count(&frame.board, Cell::O), placed);
+        }
+        assert_eq!(frames[1].board[0][0], Cell::X);
+        assert!(!frames[1].x_turn);
+        assert_eq!(frames[4].status, GameStatus::InProgress);
+        assert_eq!(frames[5].board, game.board);
+        assert_eq!(frames[5].winner, Some('X'));
+    }
+
+    #[test]
+    fn leaving_for_good_mid_game_forfeits_it() {
+        let (mut server, x, mut o) = seated();
+        // Without a timer the grace period ends straight away
+        server.handle(ServerMessage::Disconnect(x.id.clone()));
+
+        let game = &server.games["g"];
+        assert_eq!(game.status, GameStatus::Finished);
+        assert_eq!(game.winner, Some('O'));
+        let forfeit = o.drain().into_iter().any(|msg| {
+            matches!(msg, ClientMessage::GameOver { winner: Some('O'), reason: GameOverReason::OpponentForfeit, .. })
+        });
+        assert!(forfeit);
+    }
+
+    #[test]
+    fn leaving_mid_game_forfeits_it() {
+        let (mut server, x, mut o) = seated();
+        server.handle(ServerMessage::LeaveGame { player_id: x.id.clone(), game_id: "g".into() });
+
+        let game = &server.games["g"];
+        assert_eq!(game.status, GameStatus::Finished);
+        assert_eq!(game.winner, Some('O'));
+        let forfeit = o.drain().into_iter().any(|msg| {
+            matches!(msg, ClientMessage::GameOver { winner: Some('O'), reason: GameOverReason::OpponentForfeit, .. })
+        });
+        assert!(forfeit);
+    }
+
+    /// Two players seated in game `g`, which waits for both to be ready.
+    fn seated_awaiting_ready() -> (GameServer, TestClient, TestClient) {
+        let mut server = GameServer::default();
+        let mut x = TestClient::connect(&mut server);
+        let mut o = TestClient::connect(&mut server);
+        for player in [&x, &o] {
+            join_with(&mut server, player, "g", JoinOpts { ready_check: Some(true), ..Default::default() });
+        }
+        x.drain();
+        o.drain();
+        (server, x, o)
+    }
+
+    #[test]
+    fn ready_check_holds_the_game_until_both_are_ready() {
+        let (mut server, mut x, mut o) = seated_awaiting_ready();
+        assert_eq!(server.games["g"].status, GameStatus::Waiting);
+        play(&mut server, &x, "g", 0, 0);
+        assert_eq!(x.errors(), ["Game not started"]);
+
+        server.handle(ServerMessage::Ready { player_id: x.id.clone(), game_id: "g".into() });
+        assert_eq!(server.games["g"].status, GameStatus::Waiting);
+        server.handle(ServerMessage::Ready { player_id: o.id.clone(), game_id: "g".into() });
+
+        assert_eq!(server.games["g"].status, GameStatus::InProgress);
+        for client in [&mut x, &mut o] {
+            assert!(client.drain().iter().any(|msg| matches!(msg, ClientMessage::GameStarted { .. })));
+        }
+        play(&mut server, &x, "g", 0, 0);
+        assert_eq!(server.games["g"].board[0][0], Cell::X);
+    }
+
+    #[test]
+    fn rematch_in_a_ready_check_room_waits_for_ready_again() {
+        let (mut server, x, o) = seated_awaiting_ready();
+        let ready = |server: &mut GameServer, player: &TestClient| {
+            server.handle(ServerMessage::Ready { player_id: player.id.clone(), game_id: "g".into() });
+        };
+        ready(&mut server, &x);
+        ready(&mut server, &o);
+        server.handle(ServerMessage::Resign { player_id: o.id.clone(), game_id: "g".into() });
+        for player in [&x, &o] {
+            server.handle(ServerMessage::Rematch { player_id: player.id.clone(), game_id: "g".into() });
+        }
+
+        let game = &server.games["g"];
+        assert_eq!(game.status, GameStatus::Waiting);
+        assert!(game.ready.is_empty());
+        assert!(game.turn_deadline.is_none());
+
+        ready(&mut server, &x);
+        ready(&mut server, &o);
+        assert_eq!(server.games["g"].status, GameStatus::InProgress);
+    }
+
+    #[ntex::test]
+    async fn leaving_before_ready_frees_the_seat_at_once() {
+        let (mut server, x, o) = seated_awaiting_ready();
+        // With a timer, seats are normally held through the reconnect grace
+        let (tx, _rx) = tokio::sync::mpsc::channel(8);
+        server.tx = Some(tx);
+        server.handle(ServerMessage::Ready { player_id: x.id.clone(), game_id: "g".into() });
+        server.handle(ServerMessage::Disconnect(o.id.clone()));
+
+        let game = &server.games["g"];
+        assert_eq!(game.players.len(), 1);
+        assert!(game.ready.contains(&x.id));
+        assert!(server.pending_release.is_empty());
+    }
+
+    #[test]
+    fn watchers_come_and_go_like_spectators() {
+        let (mut server, mut x, _o) = seated();
+        let (tx, _rx) = tokio::sync::mpsc::channel(8);
+        server.tx = Some(tx);
+        x.drain();
+        let (sender, _events) = mpsc::unbounded();
+        let (reply, mut rx) = oneshot::channel();
+        server.handle(ServerMessage::Watch { game_id: "g".into(), sender, reply });
+        let watcher = rx.try_recv().unwrap().expect("reply").expect("watching");
+        assert!(x.drain().iter().any(|msg| {
+            matches!(msg, ClientMessage::PlayerJoined { player_id } if *player_id == watcher)
+        }));
+
+        // Nothing to resume, so no grace period
+        server.handle(ServerMessage::Disconnect(watcher.clone()));
+        assert!(!server.games["g"].spectators.contains(&watcher));
+        assert!(server.pending_release.is_empty());
+        assert!(x.drain().iter().any(|msg| {
+            matches!(msg, ClientMessage::PlayerLeft { player_id } if *player_id == watcher)
+        }));
+
+        // Watching starts no game, so a drain lets it through like a spectate join
+        let (reply, _) = oneshot::channel();
+        server.handle(ServerMessage::SetDraining { draining: true, reply });
+        let (sender, _events) = mpsc::unbounded();
+        let (reply, mut rx) = oneshot::channel();
+        server.handle(ServerMessage::Watch { game_id: "g".into(), sender, reply });
+        let watcher = rx.try_recv().unwrap().expect("reply").expect("watching");
+        assert!(server.games["g"].spectators.contains(&watcher));
+    }
+
+    #[test]
+    fn a_seat_without_a_session_cannot_move() {
+        let (mut server, x, _o) = seated();
+        server.sessions.remove(&x.id);
+        play(&mut server, &x, "g", 0, 0);
+
+        let game = &server.games["g"];
+        assert_eq!(game.board[0][0], Cell::Empty);
+        assert!(game.moves.is_empty());
+        assert!(game.x_turn);
+    }
+
+    #[test]
+    fn move_into_a_removed_game_is_answered() {
+        let (mut server, mut x, _o) = seated();
+        let (reply, _) = oneshot::channel();
+        server.handle(ServerMessage::ForceClose { game_id: "g".into(), reply });
+        x.drain();
+        play(&mut server, &x, "g", 0, 0);
+
+        assert_eq!(x.errors(), ["game no longer exists"]);
+    }
+
+    #[test]
+    fn quarantine_closes_only_the_game_that_panicked() {
+        let (mut server, mut x, mut o) = seated();
+        let mut a = TestClient::connect(&mut server);
+        let b = TestClient::connect(&mut server);
+        join(&mut server, &a, "h");
+        join(&mut server, &b, "h");
+        a.drain();
+
+        server.quarantine(Some("g"));
+        assert!(!server.games.contains_key("g"));
+        assert_eq!(x.errors(), ["Game closed after an internal error"]);
+        assert_eq!(o.errors(), ["Game closed after an internal error"]);
+        assert!(a.errors().is_empty());
+
+        // Both players are free again, and the other game plays on
+        join(&mut server, &x, "new");
+        assert!(server.games["new"].players.contains(&x.id));
+        play(&mut server, &a, "h", 0, 0);
+        assert_eq!(server.games["h"].board[0][0], Cell::X);
+    }
+
+    #[test]
+    fn disconnect_drops_the_session() {
+        let (mut server, x, _o) = seated();
+        server.handle(ServerMessage::Disconnect(x.id.clone()));
+        assert!(!server.sessions.contains_key(&x.id));
+    }
+
+    /// Every line of three on a 3x3 board, written out by hand.
+    const LINES: [[[usize; 2]; 3]; 8] = [
+        [[0, 0], [0, 1], [0, 2]],
+        [[1, 0], [1, 1], [1, 2]],
+        [[2, 0], [2, 1], [2, 2]],
+        [[0, 0], [1, 0], [2, 0]],
+        [[0, 1], [1, 1], [2, 1]],
+        [[0, 2], [1, 2], [2, 2]],
+        [[0, 0], [1, 1], [2, 2]],
+        [[0, 2], [1, 1], [2, 0]],
+    ];
+
+    /// The symbols that have completed at least one line.
+    fn reference_winners(board: &[Vec<Cell>]) -> Vec<char> {
+        let mut winners: Vec<char> = LINES
+            .iter()
+            .filter_map(|line| {
+                let [a, b, c] = line.map(|[r, col]| board[r][col]);
+                if a == b && b == c { a.symbol() } else { None }
+            })
+            .collect();
+        winners.dedup();
+        winners
+    }
+
+    fn assert_winner_matches_reference(room: &Room) {
+        let expected = reference_winners(&room.board);
+        match room.check_winner() {
+            Some((symbol, line)) => {
+                assert!(expected.contains(&symbol), "{:?} has no line for {}", room.board, symbol);
+                assert!(LINES.iter().any(|l| l.as_slice() == line.as_slice()), "bogus line {:?}", line);
+            }
+            None => assert!(expected.is_empty(), "{:?} missed a win", room.board),
+        }
+    }
+
+    #[test]
+    fn check_winner_agrees_with_reference_on_every_board() {
+        let mut room = Room::new("g".into(), 3, 3, 1);
+        for n in 0..3usize.pow(9) {
+            let mut rest = n;
+            for cell in room.board.iter_mut().flatten() {
+                *cell = [Cell::Empty, Cell::X, Cell::O][rest % 3];
+                rest /= 3;
+            }
+            assert_winner_matches_reference(&room);
+        }
+    }
+
+    #[test]
+    fn five_in_a_row_wins_on_the_diagonal_of_a_big_board() {
+        let mut room = Room::new("g".into(), 15, 5, 1);
+        // Four on the anti-diagonal is not yet a win
+        for i in 0..4 {
+            room.board[3 + i][10 - i] = Cell::X;
+            room.board[0][i] = Cell::O;
+        }
+        assert_eq!(room.check_winner(), None);
+
+        room.board[7][6] = Cell::X;
+        let (symbol, line) = room.check_winner().expect("five in a row");
+        assert_eq!(symbol, 'X');
+        assert_eq!(line, [[3, 10], [4, 9], [5, 8], [6, 7], [7, 6]]);
+    }
+
+    fn board(rows: [&str; 3]) -> Vec<Vec<Cell>> {
+        rows.iter()
+            .map(|row| row.chars().map(|c| match c {
+                'X' => Cell::X,
+                'O' => Cell::O,
+                _ => Cell::Empty,
+            }).collect())
+            .collect()
+    }
+
+    #[test]
+    fn draw_is_called_once_no_line_is_open() {
+        let mut room = Room::new("g".into(), 3, 3, 1);
+        room.board = board(["XOX", "XOO", "OX."]);
+        assert!(room.is_draw_forced());
+        room.board = board(["XOX", "XO.", "O.."]);
+        assert!(!room.is_draw_forced());
+        room.board = board(["...", "...", "..."]);
+        assert!(!room.is_draw_forced());
+    }
+
+    fn count(board: &[Vec<Cell>], cell: Cell) -> usize {
+        board.iter().flatten().filter(|c| **c == cell).count()
+    }
+
+    proptest! {
+        /// Random moves, many of them out of turn, out of bounds or onto
+        /// occupied cells, never break the board's invariants.
+        #[test]
+        fn random_moves_keep_board_invariants(
+            moves in prop::collection::vec((any::<bool>(), 0..4usize, 0..4usize), 0..40),
+        ) {
+            let (mut server, x, o) = seated();
+            for (by_x, row, col) in moves {
+                let before = server.games["g"].clone();
+                let player = if by_x { &x } else { &o };
+                play(&mut server, player, "g", row, col);
+                let after = &server.games["g"];
+
+                let placed = count(&after.board, Cell::X) + count(&after.board, Cell::O);
+                let placed_before = count(&before.board, Cell::X) + count(&before.board, Cell::O);
+                let xs = count(&after.board, Cell::X);
+                let os = count(&after.board, Cell::O);
+                prop_assert!(xs == os || xs == os + 1, "X {} vs O {}", xs, os);
+
+                if before.status == GameStatus::Finished {
+                    prop_assert_eq!(&after.board, &before.board);
+                    prop_assert_eq!(after.status, GameStatus::Finished);
+                }
+                if after.x_turn != before.x_turn {
+                    prop_assert_eq!(placed, placed_before + 1);
+                }
+                if placed == placed_before {
+                    prop_assert_eq!(after.x_turn, before.x_turn);
+                }
+                assert_winner_matches_reference(after);
+                if after.status == GameStatus::Finished && after.end_reason == Some(GameOverReason::Win) {
+                    prop_assert!(after.check_winner().is_some());
+                }
+            }
+        }
+    }
 }
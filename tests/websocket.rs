@@ -0,0 +1,342 @@
+//! End-to-end tests that run the server binary on a free port and talk to it
+//! over a real websocket.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+
+struct Server {
+    child: Child,
+    addr: String,
+}
+
+impl Server {
+    fn start() -> Server {
+        // Grab a free port from the OS and hand it to the server.
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("no free port")
+            .to_string();
+        let child = Command::new(env!("CARGO_BIN_EXE_Xo_api"))
+            .env("XO_BIND", &addr)
+            .env_remove("XO_DATABASE")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start server");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while TcpStream::connect(&addr).is_err() {
+            assert!(Instant::now() < deadline, "server did not start listening on {addr}");
+            thread::sleep(Duration::from_millis(20));
+        }
+        Server { child, addr }
+    }
+
+    /// A plain HTTP GET, answered with the JSON body.
+    fn get_json(&self, path: &str) -> Value {
+        let mut stream = TcpStream::connect(&self.addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", self.addr).unwrap();
+        let mut response = String::new();
+        std::io::Read::read_to_string(&mut stream, &mut response).unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").expect("no body in response");
+        serde_json::from_str(body).expect("server sent invalid JSON")
+    }
+
+    fn connect(&self) -> Client {
+        let (mut socket, _) = tungstenite::connect(format!("ws://{}/ws/", self.addr)).expect("websocket handshake failed");
+        if let MaybeTlsStream::Plain(stream) = socket.get_mut() {
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        }
+        let mut client = Client { socket, id: String::new() };
+        let hello = client.expect("id");
+        client.id = hello["id"].as_str().expect("id is a string").to_string();
+        assert_eq!(hello["server_version"], env!("CARGO_PKG_VERSION"));
+        assert!(hello["protocol_version"].is_u64());
+        client
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+struct Client {
+    socket: WebSocket<MaybeTlsStream<TcpStream>>,
+    id: String,
+}
+
+impl Client {
+    fn send(&mut self, msg: Value) {
+        self.socket.send(Message::text(msg.to_string())).expect("send failed");
+    }
+
+    fn recv(&mut self) -> Value {
+        loop {
+            match self.socket.read().expect("no frame from server") {
+                Message::Text(text) => return serde_json::from_str(&text).expect("server sent invalid JSON"),
+                Message::Close(frame) => panic!("server closed the connection: {frame:?}"),
+                _ => continue,
+            }
+        }
+    }
+
+    /// Read frames until one of the given `type` arrives, skipping the rest.
+    fn expect(&mut self, kind: &str) -> Value {
+        loop {
+            let msg = self.recv();
+            if msg["type"] == kind {
+                return msg;
+            }
+        }
+    }
+
+    /// Read frames until the server closes the connection, returning its close code.
+    fn close_code(&mut self) -> CloseCode {
+        loop {
+            match self.socket.read().expect("no frame from server") {
+                Message::Close(frame) => return frame.expect("close without a code").code,
+                _ => continue,
+            }
+        }
+    }
+
+    fn join(&mut self, game_id: &str) -> Value {
+        self.send(json!({ "type": "join", "player": self.id, "game_id": game_id }));
+        self.expect("game_state")
+    }
+
+    fn play(&mut self, game_id: &str, position: [usize; 2]) {
+        self.send(json!({ "type": "move", "player": self.id, "game_id": game_id, "position": position }));
+    }
+}
+
+/// Seat two clients in a fresh game, X first.
+fn seat_players(server: &Server, game_id: &str) -> (Client, Client) {
+    let mut first = server.connect();
+    let mut second = server.connect();
+    assert_eq!(first.join(game_id)["status"], "waiting");
+    let state = second.join(game_id);
+    assert_eq!(state["status"], "in_progress");
+    assert_eq!(state["symbols"][&first.id], "X");
+    assert_eq!(state["symbols"][&second.id], "O");
+    // Drain the first player's copy of the same update.
+    assert_eq!(first.expect("game_state")["board"], state["board"]);
+    assert_eq!(first.expect("turn")["is_turn"], true);
+    (first, second)
+}
+
+#[test]
+fn join_and_move_reach_both_players() {
+    let server = Server::start();
+    let (mut x, mut o) = seat_players(&server, "happy");
+
+    x.play("happy", [1, 1]);
+    let o_id = o.id.clone();
+    for client in [&mut x, &mut o] {
+        let state = client.expect("game_state");
+        assert_eq!(state["board"][1][1], "X");
+        assert_eq!(state["x_turn"], false);
+        assert_eq!(state["current_player"], o_id);
+    }
+    assert_eq!(o.expect("turn")["is_turn"], true);
+
+    // X has just moved, so a second move is refused with a reason.
+    x.play("happy", [0, 0]);
+    let error = x.expect("error");
+    assert_eq!(error["message"], "Not your turn");
+    assert_eq!(error["code"], "not_your_turn");
+    assert_eq!(error["current_player"], o_id);
+}
+
+#[test]
+fn full_game_ends_in_a_win() {
+    let server = Server::start();
+    let (mut x, mut o) = seat_players(&server, "win");
+
+    let moves = [[0, 0], [1, 0], [0, 1], [1, 1], [0, 2]];
+    for (i, position) in moves.into_iter().enumerate() {
+        if i % 2 == 0 {
+            x.play("win", position);
+        } else {
+            o.play("win", position);
+        }
+        x.expect("game_state");
+        o.expect("game_state");
+    }
+
+    for client in [&mut x, &mut o] {
+        let over = client.expect("game_over");
+        assert_eq!(over["winner"], "X");
+        assert_eq!(over["reason"], "win");
+        assert_eq!(over["line"], json!([[0, 0], [0, 1], [0, 2]]));
+        assert!(over["duration_secs"].is_u64());
+    }
+
+    x.play("win", [2, 2]);
+    assert_eq!(x.expect("error")["message"], "Game over");
+}
+
+#[test]
+fn replay_streams_one_state_per_move() {
+    let server = Server::start();
+    let (mut x, mut o) = seat_players(&server, "replay");
+    for (i, position) in [[1, 1], [0, 0]].into_iter().enumerate() {
+        if i % 2 == 0 {
+            x.play("replay", position);
+        } else {
+            o.play("replay", position);
+        }
+        x.expect("game_state");
+        o.expect("game_state");
+    }
+
+    let mut viewer = server.connect();
+    viewer.send(json!({ "type": "replay", "player": viewer.id, "game_id": "replay", "delay_ms": 10 }));
+    let boards: Vec<Value> = (0..3).map(|_| viewer.expect("game_state")["board"].clone()).collect();
+    assert_eq!(boards[0], json!([[null, null, null], [null, null, null], [null, null, null]]));
+    assert_eq!(boards[1][1][1], "X");
+    assert_eq!(boards[1][0][0], Value::Null);
+    assert_eq!(boards[2][0][0], "O");
+}
+
+#[test]
+fn hello_negotiates_features_and_drops_chat_when_left_out() {
+    let server = Server::start();
+    let (mut x, mut o) = seat_players(&server, "hello");
+
+    o.send(json!({ "type": "hello", "features": ["delta", "telepathy"] }));
+    let hello = o.expect("hello");
+    assert_eq!(hello["features"], json!(["delta"]));
+    assert_eq!(hello["server_version"], env!("CARGO_PKG_VERSION"));
+    assert!(hello["protocol_version"].is_u64());
+
+    x.send(json!({ "type": "chat", "player": x.id, "game_id": "hello", "text": "hi" }));
+    x.expect("chat");
+    x.play("hello", [1, 1]);
+    // Deltas instead of the full state, and no chat
+    let applied = loop {
+        let msg = o.recv();
+        assert_ne!(msg["type"], "chat");
+        assert_ne!(msg["type"], "game_state");
+        if msg["type"] == "move_applied" {
+            break msg;
+        }
+    };
+    assert_eq!(applied["position"], json!([1, 1]));
+}
+
+#[test]
+fn unparseable_message_is_explained() {
+    let server = Server::start();
+    let mut client = server.connect();
+
+    client.send(json!({ "type": "move", "player": client.id }));
+    let error = client.expect("error");
+    assert_eq!(error["code"], "bad_json");
+    assert!(error["detail"].as_str().unwrap().contains("game_id"));
+
+    client.send(json!({ "type": "x".repeat(4000) }));
+    let error = client.expect("error");
+    assert!(error["detail"].as_str().unwrap().chars().count() <= 201);
+}
+
+#[test]
+fn events_stream_game_states_without_a_websocket() {
+    let server = Server::start();
+    let (mut x, mut o) = seat_players(&server, "sse");
+
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    write!(stream, "GET /games/sse/events HTTP/1.1\r\nHost: {}\r\n\r\n", server.addr).unwrap();
+    let mut events = BufReader::new(stream).lines().map(|line| line.expect("no line from server"));
+    let mut next_state = || -> Value {
+        loop {
+            let line = events.next().expect("event stream ended");
+            if let Some(data) = line.strip_prefix("data: ") {
+                let msg: Value = serde_json::from_str(data).expect("server sent invalid JSON");
+                if msg["type"] == "game_state" {
+                    return msg;
+                }
+            }
+        }
+    };
+
+    assert_eq!(next_state()["status"], "in_progress");
+    x.play("sse", [2, 0]);
+    x.expect("game_state");
+    o.expect("game_state");
+    assert_eq!(next_state()["board"][2][0], "X");
+}
+
+#[test]
+fn out_of_range_move_is_refused_and_the_server_keeps_answering() {
+    let server = Server::start();
+    let (mut x, mut o) = seat_players(&server, "bounds");
+
+    x.play("bounds", [100, 100]);
+    let error = x.expect("error");
+    assert_eq!(error["code"], "out_of_bounds");
+
+    // The server is still up and the turn is still X's
+    x.play("bounds", [1, 1]);
+    assert_eq!(x.expect("game_state")["board"][1][1], "X");
+    assert_eq!(o.expect("game_state")["board"][1][1], "X");
+}
+
+#[test]
+fn closing_the_socket_removes_the_session() {
+    let server = Server::start();
+    let mut client = server.connect();
+    assert_eq!(server.get_json("/stats")["active_sessions"], 1);
+
+    client.socket.close(None).unwrap();
+    // Flush the close frame; the server answers it and then hangs up
+    while client.socket.read().is_ok() {}
+    drop(client);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.get_json("/stats")["active_sessions"] != 0 {
+        assert!(Instant::now() < deadline, "session was not removed after the socket closed");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn a_connection_cannot_act_as_another_player() {
+    let server = Server::start();
+    let (mut x, mut o) = seat_players(&server, "spoof");
+
+    // O knows X's id from the game state, but may not move for X
+    o.send(json!({ "type": "move", "player": x.id, "game_id": "spoof", "position": [1, 1] }));
+    assert_eq!(o.close_code(), CloseCode::Policy);
+
+    // X's own move still goes through on an untouched board
+    x.play("spoof", [0, 0]);
+    let state = x.expect("game_state");
+    assert_eq!(state["board"][0][0], "X");
+    assert_eq!(state["board"][1][1], Value::Null);
+    assert_eq!(state["status"], "in_progress");
+}
+
+#[test]
+fn move_sent_as_a_binary_frame_is_played() {
+    let server = Server::start();
+    let (mut x, mut o) = seat_players(&server, "binary");
+
+    let msg = json!({ "type": "move", "player": x.id, "game_id": "binary", "position": [2, 2] });
+    x.socket.send(Message::binary(msg.to_string().into_bytes())).expect("send failed");
+    for client in [&mut x, &mut o] {
+        assert_eq!(client.expect("game_state")["board"][2][2], "X");
+    }
+}